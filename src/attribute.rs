@@ -0,0 +1,133 @@
+use crate::entities::{decode_attr, escape_attr};
+
+/// Quoting of an attribute value within the opening tag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteStyle {
+    Double,
+    Single,
+    Unquoted,
+    Bare,
+}
+
+/// Single attribute of a tag, in the order and quoting it was written
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    name: String,
+    value: String,
+    quote: QuoteStyle,
+}
+
+impl Attribute {
+    /// Create new attribute, double quoted, value is escaped as needed
+    pub fn new(name: &str, value: &str) -> Self {
+        Self::with_quote(name, &escape_attr(value, '"'), QuoteStyle::Double)
+    }
+
+    /// Create new attribute with given quote style, value is as written within the source
+    pub fn with_quote(name: &str, value: &str, quote: QuoteStyle) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            quote,
+        }
+    }
+
+    /// Get attribute name, as written
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Get attribute value with character references decoded, blank string for boolean attributes
+    pub fn value(&self) -> String {
+        decode_attr(&self.value)
+    }
+
+    /// Get attribute value as written within the source, without decoding character references
+    pub fn raw_value(&self) -> String {
+        self.value.clone()
+    }
+
+    /// Get quote style of value
+    pub fn quote(&self) -> QuoteStyle {
+        self.quote
+    }
+
+    /// Check if attribute has name, case-insensitive as per HTML
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Set attribute value, escaping it for the quote style which is kept where the new value allows it
+    pub fn set_value(&mut self, value: &str) {
+        let fits = match self.quote {
+            QuoteStyle::Double | QuoteStyle::Single => true,
+            QuoteStyle::Unquoted => {
+                !value.is_empty() && !value.contains(|c: char| c.is_ascii_whitespace() || "\"'=`".contains(c))
+            }
+            QuoteStyle::Bare => value.is_empty(),
+        };
+        if !fits {
+            self.quote = QuoteStyle::Double;
+        }
+
+        let quote = if self.quote == QuoteStyle::Single { '\'' } else { '"' };
+        self.value = escape_attr(value, quote);
+    }
+
+    /// Render attribute as HTML
+    pub fn render(&self) -> String {
+        match self.quote {
+            QuoteStyle::Double => format!("{}=\"{}\"", self.name, self.value),
+            QuoteStyle::Single => format!("{}='{}'", self.name, self.value),
+            QuoteStyle::Unquoted => format!("{}={}", self.name, self.value),
+            QuoteStyle::Bare => self.name.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Attribute, QuoteStyle};
+    use crate::parse_html;
+
+    #[test]
+    fn render_keeps_quote_style() {
+        assert_eq!(Attribute::with_quote("a", "1", QuoteStyle::Double).render(), "a=\"1\"");
+        assert_eq!(Attribute::with_quote("a", "1", QuoteStyle::Single).render(), "a='1'");
+        assert_eq!(Attribute::with_quote("a", "1", QuoteStyle::Unquoted).render(), "a=1");
+        assert_eq!(Attribute::with_quote("a", "", QuoteStyle::Bare).render(), "a");
+        assert_eq!(Attribute::new("title", "say \"hi\" & go").render(), "title=\"say &quot;hi&quot; &amp; go\"");
+    }
+
+    #[test]
+    fn set_value_keeps_quote_style_where_it_fits() {
+        let mut attr = Attribute::with_quote("a", "1", QuoteStyle::Single);
+        attr.set_value("it's");
+        assert_eq!(attr.render(), "a='it&#39;s'");
+
+        let mut attr = Attribute::with_quote("a", "1", QuoteStyle::Unquoted);
+        attr.set_value("2");
+        assert_eq!(attr.render(), "a=2");
+        attr.set_value("2 3");
+        assert_eq!((attr.render(), attr.quote()), ("a=\"2 3\"".to_string(), QuoteStyle::Double));
+
+        let mut attr = Attribute::with_quote("hidden", "", QuoteStyle::Bare);
+        attr.set_value("");
+        assert_eq!(attr.render(), "hidden");
+        attr.set_value("until-found");
+        assert_eq!(attr.render(), "hidden=\"until-found\"");
+        assert_eq!(attr.value(), "until-found");
+    }
+
+    #[test]
+    fn modified_tags_keep_attribute_order_and_quoting() {
+        let mut stack = parse_html("<a Z=1 href='/x' data-b=\"y\" download>link</a>");
+        let id = stack.query().tag("a").to_vec()[0].id();
+        let mut token = stack.get_mut(&id).unwrap();
+        token.set_attr("href", "/y");
+        token.set_attr("class", "new");
+        token.del_attr("data-b");
+
+        assert_eq!(stack.render(), "<a Z=1 href='/y' download class=\"new\">link</a>");
+    }
+}
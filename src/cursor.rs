@@ -0,0 +1,151 @@
+use crate::stack::Stack;
+
+/// Position within a walk of the stack in hierarchial order, top to bottom, left to right.  Kept outside
+/// of the stack, so any number of walks can run over a shared `&Stack` at once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cursor {
+    parent_id: usize,
+    position: usize,
+    path: Vec<usize>,
+    excludes: Vec<usize>,
+    is_children_only: bool,
+    is_done: bool,
+}
+
+impl Cursor {
+    /// Instantiate cursor to walk all descendants of parent id, or the whole document if 0
+    pub fn new(parent_id: &usize) -> Self {
+        Self {
+            parent_id: *parent_id,
+            position: *parent_id,
+            path: Vec::new(),
+            excludes: Vec::new(),
+            is_children_only: false,
+            is_done: false,
+        }
+    }
+
+    /// Set tokens to skip, along with all of their descendants
+    pub fn excludes(mut self, token_ids: &[usize]) -> Self {
+        self.excludes = token_ids.to_vec();
+        self
+    }
+
+    /// Only walk direct children of parent id, instead of all descendants
+    pub fn children_only(mut self) -> Self {
+        self.is_children_only = true;
+        self
+    }
+
+    /// Get tokens skipped
+    pub fn excluded(&self) -> &[usize] {
+        &self.excludes
+    }
+
+    /// Get id of the last token walked, or the parent id if not yet started
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Move to the next token and return its id, or None once all descendants of parent id have been walked
+    pub fn next_id(&mut self, stack: &Stack) -> Option<usize> {
+        if self.is_done {
+            return None;
+        }
+
+        // Descend into children of current token
+        if !self.is_children_only || self.position == self.parent_id {
+            let children = stack.child_ids(&self.position);
+            if let Some(index) = children.iter().position(|id| !self.excludes.contains(id)) {
+                self.position = children[index];
+                self.path.push(index);
+                return Some(self.position);
+            }
+        }
+
+        // Move to next sibling, going up the tree until one is found
+        let mut current = self.position;
+        while current != self.parent_id {
+            let parent_id = match stack.tokens.get(&current) {
+                Some(token) => token.parent_id(),
+                None => break,
+            };
+            let siblings = stack.child_ids(&parent_id);
+
+            // Index of current token is kept along the path, unless the tree was modified since
+            let index = match self.path.pop() {
+                Some(index) if siblings.get(index) == Some(&current) => index + 1,
+                _ => siblings.iter().position(|id| *id == current).map(|r| r + 1).unwrap_or(siblings.len()),
+            };
+            if let Some(offset) = siblings[index..].iter().position(|id| !self.excludes.contains(id)) {
+                self.position = siblings[index + offset];
+                self.path.push(index + offset);
+                return Some(self.position);
+            }
+            current = parent_id;
+        }
+
+        self.is_done = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cursor;
+    use crate::{parse_html, Stack};
+
+    /// Walk cursor to the end, getting tags of elements and text of other nodes
+    fn walk(stack: &Stack, mut cursor: Cursor) -> Vec<String> {
+        let mut res = Vec::new();
+        while let Some(token_id) = cursor.next_id(stack) {
+            let token = stack.get(&token_id).unwrap();
+            res.push(if token.is_element() { token.tag() } else { token.contents() });
+        }
+        res
+    }
+
+    #[test]
+    fn walk_in_document_order() {
+        let stack = parse_html("<div><p>a<b>b</b></p><i>c</i></div><span>d</span>");
+        assert_eq!(walk(&stack, Cursor::new(&0)), ["div", "p", "a", "b", "b", "i", "c", "span", "d"]);
+
+        let div = stack.query().tag("div").ids()[0];
+        assert_eq!(walk(&stack, Cursor::new(&div)), ["p", "a", "b", "b", "i", "c"]);
+        assert_eq!(walk(&stack, Cursor::new(&div).children_only()), ["p", "i"]);
+
+        let p = stack.query().tag("p").ids()[0];
+        assert_eq!(walk(&stack, Cursor::new(&0).excludes(&[p])), ["div", "i", "c", "span", "d"]);
+        assert_eq!(Cursor::new(&0).excludes(&[p]).excluded(), [p]);
+    }
+
+    #[test]
+    fn position_and_end() {
+        let stack = parse_html("<p>a</p>");
+        let mut cursor = Cursor::new(&0);
+        assert_eq!(cursor.position(), 0);
+        let p = cursor.next_id(&stack).unwrap();
+        assert_eq!(cursor.position(), p);
+        assert!(cursor.next_id(&stack).is_some());
+        assert_eq!(cursor.next_id(&stack), None);
+        assert_eq!(cursor.next_id(&stack), None);
+    }
+
+    #[test]
+    fn independent_cursors_over_modified_tree() {
+        let mut stack = parse_html("<ul><li>a</li><li>b</li><li>c</li></ul>");
+        let li = stack.query().tag("li").ids();
+        let mut first = Cursor::new(&0);
+        let mut second = Cursor::new(&0);
+        for _ in 0..4 {
+            first.next_id(&stack);
+        }
+        assert_eq!(first.position(), li[1]);
+        assert_eq!(second.next_id(&stack), stack.query().tag("ul").ids().first().copied());
+
+        // Siblings before the position are removed, the walk continues after it
+        stack.remove(&li[0]);
+        assert_eq!(walk(&stack, first), ["b", "li", "c"]);
+        assert_eq!(walk(&stack, second), ["li", "b", "li", "c"]);
+    }
+}
@@ -0,0 +1,144 @@
+/// Document type declaration, eg. `<!DOCTYPE html>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Doctype {
+    name: String,
+    public_id: Option<String>,
+    system_id: Option<String>,
+}
+
+impl Doctype {
+    /// Create new doctype
+    pub fn new(name: &str, public_id: Option<&str>, system_id: Option<&str>) -> Self {
+        Self {
+            name: name.to_ascii_lowercase(),
+            public_id: public_id.map(|id| id.to_string()),
+            system_id: system_id.map(|id| id.to_string()),
+        }
+    }
+
+    /// Parse doctype from its source, eg. `<!DOCTYPE html PUBLIC "-//W3C//DTD HTML 4.01//EN" "http://www.w3.org/TR/html4/strict.dtd">`
+    pub fn parse(source: &str) -> Self {
+        let body = source.get(9..).unwrap_or("");
+        let body = body.strip_suffix('>').unwrap_or(body);
+        let mut rest = body.trim_start();
+
+        // Get name
+        let name_end = rest.find(|c: char| c.is_ascii_whitespace()).unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        // Get public and system identifiers
+        let mut public_id = None;
+        let mut system_id = None;
+        if starts_with_keyword(rest, "public") {
+            rest = rest[6..].trim_start();
+            public_id = quoted(&mut rest);
+            system_id = quoted(&mut rest);
+        } else if starts_with_keyword(rest, "system") {
+            rest = rest[6..].trim_start();
+            system_id = quoted(&mut rest);
+        }
+
+        Self { name, public_id, system_id }
+    }
+
+    /// Get name, eg. 'html'
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Get public identifier
+    pub fn public_id(&self) -> Option<String> {
+        self.public_id.clone()
+    }
+
+    /// Get system identifier
+    pub fn system_id(&self) -> Option<String> {
+        self.system_id.clone()
+    }
+
+    /// Render doctype as HTML
+    pub fn render(&self) -> String {
+        let mut html = format!("<!DOCTYPE {}", self.name);
+        match (&self.public_id, &self.system_id) {
+            (Some(public_id), Some(system_id)) => {
+                html.push_str(&format!(" PUBLIC {} {}", quote(public_id), quote(system_id)))
+            }
+            (Some(public_id), None) => html.push_str(&format!(" PUBLIC {}", quote(public_id))),
+            (None, Some(system_id)) => html.push_str(&format!(" SYSTEM {}", quote(system_id))),
+            (None, None) => {}
+        }
+        html.push('>');
+        html
+    }
+}
+
+/// Check if text starts with keyword, case-insensitive
+fn starts_with_keyword(text: &str, keyword: &str) -> bool {
+    text.len() >= keyword.len() && text.as_bytes()[..keyword.len()].eq_ignore_ascii_case(keyword.as_bytes())
+}
+
+/// Read quoted identifier from start of text, and move past it
+fn quoted(text: &mut &str) -> Option<String> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let end = text[1..].find(quote).map(|r| r + 1).unwrap_or(text.len());
+    let id = text[1..end].to_string();
+    *text = text.get(end + 1..).unwrap_or("").trim_start();
+    Some(id)
+}
+
+/// Quote identifier, using single quotes if it contains a double quote
+fn quote(id: &str) -> String {
+    if id.contains('"') {
+        format!("'{}'", id)
+    } else {
+        format!("\"{}\"", id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Doctype;
+    use crate::{parse_html, NodeKind};
+
+    #[test]
+    fn parse_doctypes() {
+        let doctype = Doctype::parse("<!DOCTYPE html>");
+        assert_eq!((doctype.name(), doctype.public_id(), doctype.system_id()), ("html".to_string(), None, None));
+
+        let doctype = Doctype::parse(
+            "<!doctype HTML public \"-//W3C//DTD HTML 4.01//EN\" 'http://www.w3.org/TR/html4/strict.dtd'>",
+        );
+        assert_eq!(doctype.name(), "html");
+        assert_eq!(doctype.public_id(), Some("-//W3C//DTD HTML 4.01//EN".to_string()));
+        assert_eq!(doctype.system_id(), Some("http://www.w3.org/TR/html4/strict.dtd".to_string()));
+
+        let doctype = Doctype::parse("<!DOCTYPE svg SYSTEM \"svg.dtd\">");
+        assert_eq!((doctype.public_id(), doctype.system_id()), (None, Some("svg.dtd".to_string())));
+    }
+
+    #[test]
+    fn render_doctypes() {
+        assert_eq!(Doctype::new("HTML", None, None).render(), "<!DOCTYPE html>");
+        assert_eq!(Doctype::new("html", Some("a"), Some("b")).render(), "<!DOCTYPE html PUBLIC \"a\" \"b\">");
+        assert_eq!(Doctype::new("html", None, Some("b")).render(), "<!DOCTYPE html SYSTEM \"b\">");
+    }
+
+    #[test]
+    fn doctype_node_on_stack() {
+        let mut stack = parse_html("<!doctype html><p>x</p>");
+        let token = stack.query().kind(NodeKind::Doctype).to_vec()[0];
+        assert_eq!(token.doctype(), Some(Doctype::new("html", None, None)));
+        assert_eq!(stack.doctype().unwrap().name(), "html");
+        assert_eq!(stack.render(), "<!doctype html><p>x</p>");
+
+        stack.set_doctype(&Doctype::new("html", Some("a"), None));
+        assert_eq!(stack.render(), "<!DOCTYPE html PUBLIC \"a\"><p>x</p>");
+
+        let mut stack = parse_html("<p>x</p>");
+        assert_eq!(stack.doctype(), None);
+        stack.set_doctype(&Doctype::new("html", None, None));
+        assert_eq!(stack.render(), "<!DOCTYPE html><p>x</p>");
+        assert_eq!(stack.query().kind(NodeKind::Doctype).to_vec().len(), 1);
+    }
+}
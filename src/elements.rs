@@ -0,0 +1,81 @@
+/// Void elements, which never have contents or a closing tag
+pub const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Raw text elements, contents are kept verbatim and no tags are recognized
+pub const RAW_TEXT_ELEMENTS: [&str; 6] = ["script", "style", "xmp", "iframe", "noembed", "noframes"];
+
+/// Escapable raw text (RCDATA) elements, entities are decoded but no tags are recognized
+pub const RCDATA_ELEMENTS: [&str; 2] = ["textarea", "title"];
+
+/// Check if tag is a raw text element
+pub fn is_raw_text(tag: &str) -> bool {
+    RAW_TEXT_ELEMENTS.contains(&tag)
+}
+
+/// Check if tag is an RCDATA element
+pub fn is_rcdata(tag: &str) -> bool {
+    RCDATA_ELEMENTS.contains(&tag)
+}
+
+/// Check if tag is a void element
+pub fn is_void(tag: &str) -> bool {
+    VOID_ELEMENTS.contains(&tag)
+}
+
+/// Special elements, as per the HTML tree construction rules
+pub const SPECIAL_ELEMENTS: [&str; 83] = [
+    "address", "applet", "area", "article", "aside", "base", "basefont", "bgsound", "blockquote",
+    "body", "br", "button", "caption", "center", "col", "colgroup", "dd", "details", "dir", "div",
+    "dl", "dt", "embed", "fieldset", "figcaption", "figure", "footer", "form", "frame", "frameset",
+    "h1", "h2", "h3", "h4", "h5", "h6", "head", "header", "hgroup", "hr", "html", "iframe", "img",
+    "input", "keygen", "li", "link", "listing", "main", "marquee", "menu", "meta", "nav",
+    "noembed", "noframes", "noscript", "object", "ol", "p", "param", "plaintext", "pre", "script",
+    "search", "section", "select", "source", "style", "summary", "table", "tbody", "td",
+    "template", "textarea", "tfoot", "th", "thead", "title", "tr", "track", "ul", "wbr", "xmp",
+];
+
+/// Formatting elements, which are re-opened when misnested
+pub const FORMATTING_ELEMENTS: [&str; 14] = [
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+];
+
+/// Elements whose end tag may be omitted, so closing them implicitly is not an error
+pub const OPTIONAL_END_TAGS: [&str; 21] = [
+    "body", "caption", "colgroup", "dd", "dt", "head", "html", "li", "optgroup", "option", "p",
+    "rb", "rp", "rt", "rtc", "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+/// Start tags which close an open p element
+pub const CLOSES_P: [&str; 41] = [
+    "address", "article", "aside", "blockquote", "center", "dd", "details", "dialog", "dir", "div",
+    "dl", "dt", "fieldset", "figcaption", "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5",
+    "h6", "header", "hgroup", "hr", "li", "listing", "main", "menu", "nav", "ol", "p", "plaintext",
+    "pre", "search", "section", "summary", "table", "ul", "xmp",
+];
+
+/// Headings, any of which closes another
+pub const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// Elements which insert a marker into the list of active formatting elements
+pub const MARKERS: [&str; 7] = ["applet", "caption", "marquee", "object", "td", "template", "th"];
+
+/// Elements that bound the default scope
+pub const SCOPE_BOUNDARIES: [&str; 9] = ["applet", "caption", "html", "table", "td", "th", "marquee", "object", "template"];
+
+/// Check if tag is a special element
+pub fn is_special(tag: &str) -> bool {
+    SPECIAL_ELEMENTS.contains(&tag)
+}
+
+/// Check if tag is a formatting element
+pub fn is_formatting(tag: &str) -> bool {
+    FORMATTING_ELEMENTS.contains(&tag)
+}
+
+/// Check if tag is a heading
+pub fn is_heading(tag: &str) -> bool {
+    HEADINGS.contains(&tag)
+}
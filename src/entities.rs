@@ -0,0 +1,300 @@
+use std::ops::Range;
+
+mod table;
+
+/// Replacements for numeric references to C1 control characters, as per the HTML spec
+const C1_REPLACEMENTS: [(u32, char); 27] = [
+    (0x80, '\u{20ac}'),
+    (0x82, '\u{201a}'),
+    (0x83, '\u{0192}'),
+    (0x84, '\u{201e}'),
+    (0x85, '\u{2026}'),
+    (0x86, '\u{2020}'),
+    (0x87, '\u{2021}'),
+    (0x88, '\u{02c6}'),
+    (0x89, '\u{2030}'),
+    (0x8a, '\u{0160}'),
+    (0x8b, '\u{2039}'),
+    (0x8c, '\u{0152}'),
+    (0x8e, '\u{017d}'),
+    (0x91, '\u{2018}'),
+    (0x92, '\u{2019}'),
+    (0x93, '\u{201c}'),
+    (0x94, '\u{201d}'),
+    (0x95, '\u{2022}'),
+    (0x96, '\u{2013}'),
+    (0x97, '\u{2014}'),
+    (0x98, '\u{02dc}'),
+    (0x99, '\u{2122}'),
+    (0x9a, '\u{0161}'),
+    (0x9b, '\u{203a}'),
+    (0x9c, '\u{0153}'),
+    (0x9e, '\u{017e}'),
+    (0x9f, '\u{0178}'),
+];
+
+/// Decode character references (eg. `&amp;`, `&#39;`, `&#x1F600;`) within text
+pub fn decode(text: &str) -> String {
+    decode_with(text, false)
+}
+
+/// Decode character references within an attribute value, where legacy references without a semicolon
+/// followed by '=' or an alphanumeric character are left as is (eg. `?a=1&copy=2`)
+pub fn decode_attr(value: &str) -> String {
+    decode_with(value, true)
+}
+
+/// Escape text for use between tags
+pub fn escape_text(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// Escape text for use within a raw text element (eg. script, style), which can not contain references.  End tags of
+/// the element, matched case-insensitively, are written as `<\/tag` so they can not close it early.
+pub fn escape_raw_text(text: &str, tag: &str) -> String {
+    let end_tag = format!("</{}", tag.to_ascii_lowercase());
+    let mut res = String::with_capacity(text.len());
+    let mut last = 0;
+    for (index, _) in text.to_ascii_lowercase().match_indices(&end_tag) {
+        res.push_str(&text[last..index]);
+        res.push_str("<\\/");
+        last = index + 2;
+    }
+    res.push_str(&text[last..]);
+    res
+}
+
+/// Escape attribute value for use within the given quote character
+pub fn escape_attr(value: &str, quote: char) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' if quote == '"' => res.push_str("&quot;"),
+            '\'' if quote == '\'' => res.push_str("&#39;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// Get byte ranges of character references within text that are not well formed, eg. `&#xZZ;`, `&bogus;` or `&amp` missing its semicolon
+pub fn invalid_references(text: &str) -> Vec<Range<usize>> {
+    invalid_references_with(text, false)
+}
+
+/// Get byte ranges of character references within attribute values that are not well formed
+pub fn invalid_attr_references(value: &str) -> Vec<Range<usize>> {
+    invalid_references_with(value, true)
+}
+
+/// Get byte ranges of character references that are not well formed, within text or an attribute value
+fn invalid_references_with(text: &str, is_attr: bool) -> Vec<Range<usize>> {
+    let mut res = Vec::new();
+    let mut index = 0;
+
+    while let Some(offset) = text[index..].find('&') {
+        let start = index + offset;
+        let rest = &text[start..];
+
+        // Absence of digits within numeric reference
+        if rest.starts_with("&#") && decode_reference(rest).is_none() {
+            let len = if rest[2..].starts_with(['x', 'X']) { 3 } else { 2 };
+            res.push(start..start + len);
+            index = start + len;
+            continue;
+        }
+
+        index = match decode_reference(rest) {
+            Some((_, len, is_valid)) => {
+                if !is_valid && (!is_attr || !is_legacy_in_attr(rest, len)) {
+                    res.push(start..start + len);
+                }
+                start + len
+            }
+            None => {
+                // Unknown named reference
+                let len = rest[1..].find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len() - 1);
+                if len > 0 && rest[1 + len..].starts_with(';') {
+                    res.push(start..start + len + 2);
+                }
+                start + 1 + len
+            }
+        };
+    }
+
+    res
+}
+
+/// Decode character references within text, or within an attribute value
+fn decode_with(text: &str, is_attr: bool) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find('&') {
+        res.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        match decode_reference(rest) {
+            Some((_, len, false)) if is_attr && is_legacy_in_attr(rest, len) => {
+                res.push_str(&rest[..len]);
+                rest = &rest[len..];
+            }
+            Some((value, len, _)) => {
+                res.push_str(&value);
+                rest = &rest[len..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    res.push_str(rest);
+    res
+}
+
+/// Check if named reference without semicolon is followed by '=' or an alphanumeric character, and not decoded within attributes
+fn is_legacy_in_attr(text: &str, len: usize) -> bool {
+    !text.starts_with("&#")
+        && text[len..].starts_with(|c: char| c == '=' || c.is_ascii_alphanumeric())
+}
+
+/// Decode single character reference at start of string, returns decoded text, length consumed, and whether it was well formed
+fn decode_reference(text: &str) -> Option<(String, usize, bool)> {
+    let body = &text[1..];
+
+    // Numeric reference
+    if let Some(num) = body.strip_prefix('#') {
+        let (digits, radix, offset) = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => (hex, 16, 3),
+            None => (num, 10, 2),
+        };
+        let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+        if len == 0 {
+            return None;
+        }
+
+        let code = u32::from_str_radix(&digits[..len], radix).unwrap_or(u32::MAX);
+        let (chr, is_valid) = decode_code_point(code);
+        let semicolon = if digits[len..].starts_with(';') { 1 } else { 0 };
+        return Some((chr.to_string(), offset + len + semicolon, is_valid && semicolon == 1));
+    }
+
+    // Named reference, with semicolon
+    let len = body.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(body.len());
+    if len == 0 {
+        return None;
+    } else if body[len..].starts_with(';') {
+        if let Some(value) = lookup(&body[..len + 1]) {
+            return Some((value.to_string(), len + 2, true));
+        }
+    }
+
+    // Longest legacy name without semicolon
+    for end in (1..=len).rev() {
+        if let Some(value) = lookup(&body[..end]) {
+            return Some((value.to_string(), end + 1, false));
+        }
+    }
+
+    None
+}
+
+/// Get character of numeric reference, and whether it is allowed.  Null, surrogate and out of range code points are replaced.
+fn decode_code_point(code: u32) -> (char, bool) {
+    if let Some((_, chr)) = C1_REPLACEMENTS.iter().find(|(c1, _)| *c1 == code) {
+        return (*chr, false);
+    }
+
+    match code {
+        0 => ('\u{fffd}', false),
+        0x01..=0x08 | 0x0b | 0x0e..=0x1f | 0x7f..=0x9f => (char::from_u32(code).unwrap(), false),
+        _ => match char::from_u32(code) {
+            Some(chr) => (chr, true),
+            None => ('\u{fffd}', false),
+        },
+    }
+}
+
+/// Look up named character reference
+fn lookup(name: &str) -> Option<&'static str> {
+    table::NAMED
+        .binary_search_by(|(entity, _)| entity.cmp(&name))
+        .ok()
+        .map(|index| table::NAMED[index].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_attr, escape_attr, escape_raw_text, escape_text, invalid_attr_references, invalid_references, table, Range};
+    use crate::parse_html;
+
+    #[test]
+    fn decode_named_and_numeric() {
+        assert_eq!(decode("a &amp; b &lt;c&gt; &quot;&#39;&#x27;&#X27;"), "a & b <c> \"'''");
+        assert_eq!(decode("&NotEqualTilde; &#x1F600; &#128512;"), "\u{2242}\u{338} \u{1f600} \u{1f600}");
+        assert_eq!(decode("&bogus; & &; &#; &#x;"), "&bogus; & &; &#; &#x;");
+    }
+
+    #[test]
+    fn decode_legacy_and_invalid_code_points() {
+        assert_eq!(decode("&copy 2024 &notit; &amp"), "\u{a9} 2024 \u{ac}it; &");
+        assert_eq!(decode("&#0;&#xD800;&#x110000;&#128;&#65"), "\u{fffd}\u{fffd}\u{fffd}\u{20ac}A");
+        assert_eq!(invalid_references("&amp &#0; &#65; &bogus; &#x;"), [0..4, 5..9, 16..23, 24..27]);
+    }
+
+    #[test]
+    fn legacy_references_within_attributes() {
+        assert_eq!(decode_attr("?a=1&copy=2&not;x&amp=3"), "?a=1&copy=2\u{ac}x&amp=3");
+        assert_eq!(decode_attr("&copy 2024&lt"), "\u{a9} 2024<");
+        assert_eq!(decode("?a=1&copy=2"), "?a=1\u{a9}=2");
+        assert!(invalid_attr_references("?a=1&copy=2").is_empty());
+        assert_eq!(invalid_attr_references("&copy 2024"), vec![Range { start: 0, end: 5 }]);
+
+        let stack = parse_html("<a href=\"?x=1&copy=2&amp;y=&lt;\" title='&copy 2024'>");
+        let a = stack.query().tag("a").to_vec()[0];
+        assert_eq!(a.attr("href"), Some("?x=1&copy=2&y=<".to_string()));
+        assert_eq!(a.attr("title"), Some("\u{a9} 2024".to_string()));
+    }
+
+    #[test]
+    fn escape_on_write() {
+        assert_eq!(escape_text("a < b && \"c\" > 'd'"), "a &lt; b &amp;&amp; \"c\" &gt; 'd'");
+        assert_eq!(escape_attr("\"it's\" <&>", '"'), "&quot;it's&quot; &lt;&amp;&gt;");
+        assert_eq!(escape_attr("\"it's\"", '\''), "\"it&#39;s\"");
+        assert_eq!(decode(&escape_text("&amp; <b>")), "&amp; <b>");
+
+        let mut stack = parse_html("<p title=x>old</p>");
+        let id = stack.query().tag("p").to_vec()[0].id();
+        stack.set_text(&id, "1 < 2 & 3");
+        stack.get_mut(&id).unwrap().set_attr("title", "a \"b\" & c");
+        assert_eq!(stack.render(), "<p title=\"a &quot;b&quot; &amp; c\">1 &lt; 2 &amp; 3</p>");
+        assert_eq!(stack.get_text(&id), "1 < 2 & 3");
+
+        let mut stack = parse_html("<script>x</script><b>z</b>");
+        let id = stack.query().tag("script").to_vec()[0].id();
+        stack.set_text(&id, "a</script><b>y</b></SCRIPT >");
+        assert_eq!(stack.render(), "<script>a<\\/script><b>y</b><\\/SCRIPT ></script><b>z</b>");
+        let stack = parse_html(&stack.render());
+        assert_eq!(stack.query().tag("b").to_vec().len(), 1);
+        assert_eq!(escape_raw_text("a </style> </styles", "style"), "a <\\/style> <\\/styles");
+    }
+
+    #[test]
+    fn named_table_is_sorted() {
+        assert!(table::NAMED.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::span::Span;
+use std::fmt;
+
+/// Kind of error encountered while parsing, names follow the HTML parse errors where possible
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnexpectedEndTag(String),
+    MissingEndTag(String),
+    MisnestedTag(String),
+    NonVoidSelfClosingTag(String),
+    DuplicateAttribute(String),
+    InvalidAttributeName(String),
+    InvalidCharacterReference(String),
+    EofInTag,
+    EofInComment,
+    EofInDoctype,
+    EofInCData,
+    AbruptClosingOfEmptyComment,
+    IncorrectlyOpenedComment,
+    IncorrectlyClosedComment,
+    InvalidFirstCharacterOfTagName,
+    MissingEndTagName,
+    EndTagWithAttributes,
+    EndTagWithTrailingSolidus,
+}
+
+/// Error encountered while parsing, with the span of source it applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    kind: ParseErrorKind,
+    span: Span,
+}
+
+impl ParseError {
+    /// Create new parse error
+    pub fn new(kind: ParseErrorKind, span: &Span) -> Self {
+        Self { kind, span: *span }
+    }
+
+    /// Get kind of error
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind.clone()
+    }
+
+    /// Get byte span within the source
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEndTag(tag) => write!(f, "unexpected end tag </{}>", tag),
+            Self::MissingEndTag(tag) => write!(f, "missing end tag for <{}>", tag),
+            Self::MisnestedTag(tag) => write!(f, "misnested end tag </{}>", tag),
+            Self::NonVoidSelfClosingTag(tag) => write!(f, "self-closing syntax on non-void element <{}>", tag),
+            Self::DuplicateAttribute(name) => write!(f, "duplicate attribute '{}'", name),
+            Self::InvalidAttributeName(name) => write!(f, "invalid attribute name '{}'", name),
+            Self::InvalidCharacterReference(text) => write!(f, "invalid character reference '{}'", text),
+            Self::EofInTag => write!(f, "end of file in tag"),
+            Self::EofInComment => write!(f, "end of file in comment"),
+            Self::EofInDoctype => write!(f, "end of file in doctype"),
+            Self::EofInCData => write!(f, "end of file in CDATA section"),
+            Self::AbruptClosingOfEmptyComment => write!(f, "abrupt closing of empty comment"),
+            Self::IncorrectlyOpenedComment => write!(f, "incorrectly opened comment"),
+            Self::IncorrectlyClosedComment => write!(f, "incorrectly closed comment"),
+            Self::InvalidFirstCharacterOfTagName => write!(f, "invalid first character of tag name"),
+            Self::MissingEndTagName => write!(f, "missing end tag name"),
+            Self::EndTagWithAttributes => write!(f, "end tag with attributes"),
+            Self::EndTagWithTrailingSolidus => write!(f, "end tag with trailing solidus"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at bytes {}..{}", self.kind, self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Error parsing a CSS selector, with the byte position of the offending character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError {
+    selector: String,
+    position: usize,
+}
+
+impl SelectorError {
+    /// Create new selector error
+    pub fn new(selector: &str, position: &usize) -> Self {
+        Self {
+            selector: selector.to_string(),
+            position: *position,
+        }
+    }
+
+    /// Get selector that failed to parse
+    pub fn selector(&self) -> String {
+        self.selector.clone()
+    }
+
+    /// Get byte position of the offending character within the selector
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.selector[self.position..].chars().next() {
+            Some(c) => write!(f, "invalid selector '{}', unexpected '{}' at position {}", self.selector, c, self.position),
+            None => write!(f, "invalid selector '{}', unexpected end at position {}", self.selector, self.position),
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+/// Error parsing an XPath expression, with the byte position of the offending character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XPathError {
+    expression: String,
+    position: usize,
+}
+
+impl XPathError {
+    /// Create new XPath error
+    pub fn new(expression: &str, position: &usize) -> Self {
+        Self {
+            expression: expression.to_string(),
+            position: *position,
+        }
+    }
+
+    /// Get expression that failed to parse
+    pub fn expression(&self) -> String {
+        self.expression.clone()
+    }
+
+    /// Get byte position of the offending character within the expression
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for XPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expression[self.position..].chars().next() {
+            Some(c) => write!(f, "invalid XPath '{}', unexpected '{}' at position {}", self.expression, c, self.position),
+            None => write!(f, "invalid XPath '{}', unexpected end at position {}", self.expression, self.position),
+        }
+    }
+}
+
+impl std::error::Error for XPathError {}
@@ -1,82 +1,161 @@
-#![allow(warnings)]
-use regex::{Regex, RegexBuilder};
+pub use self::attribute::{Attribute, QuoteStyle};
+pub use self::cursor::Cursor;
+pub use self::doctype::Doctype;
+pub use self::error::{ParseError, ParseErrorKind, SelectorError, XPathError};
+pub use self::selector::Selector;
+pub use self::span::Span;
+pub use self::stack::{Position, RenderStyle, Stack};
+pub use self::token::{NodeKind, Token};
+pub use self::token_mut::TokenMut;
+pub use self::xpath::{XPath, XPathValue};
+use self::tokenizer::{scan_attributes, RawToken, Tokenizer};
 use std::collections::HashMap;
-pub use self::stack::Stack;
-pub use self::token::Token;
 
+pub mod attribute;
+pub mod cursor;
+pub mod doctype;
+pub mod elements;
+pub mod entities;
+pub mod error;
 pub mod query;
+pub mod selector;
+pub mod span;
 pub mod stack;
 pub mod token;
 pub mod token_iter;
+pub mod token_mut;
+pub mod tokenizer;
+pub mod xpath;
 
 
 /// Parse block of HTML code into a token stack
 pub fn parse_html(html: &str) -> Stack {
-    // Start token stack
+    parse_html_with_diagnostics(html).0
+}
+
+/// Parse block of HTML code into a token stack, along with any errors encountered in source order
+pub fn parse_html_with_diagnostics(html: &str) -> (Stack, Vec<ParseError>) {
     let mut stack = Stack::new(html);
+    let errors = build_stack(&mut stack, html);
+    (stack, errors)
+}
 
-    // Extract comments
-    let re = RegexBuilder::new(r"(?s)<!--(.*?)-->")
-        .dot_matches_new_line(true)
-        .build()
-        .unwrap();
-    for cap in re.captures_iter(html) {
-        let tag_string = cap.get(0).unwrap().as_str();
-        stack.push("!", "", &true, tag_string);
+/// Parse HTML fragment as the contents of a context element (eg. 'ul', 'tr'), which is the first token of the stack.
+/// A blank context parses the fragment as a document.
+pub(crate) fn parse_fragment(html: &str, context: &str) -> Stack {
+    let mut stack = Stack::new(html);
+    if !context.is_empty() {
+        stack.push(context, "", &false, "");
     }
+    build_stack(&mut stack, html);
+    stack
+}
 
-    // Go through tags
-    let re = Regex::new(r"<([\/]?)(.*?)([\/]?)>").unwrap();
-    for cap in re.captures_iter(html) {
-        // Set variables
-        let is_closing: bool = cap.get(1).unwrap().as_str() == "/";
-        let mut tag = cap.get(2).unwrap().as_str().trim();
-        let is_single: bool = cap.get(3).unwrap().as_str() == "/";
-        let tag_string = cap.get(0).unwrap().as_str();
-
-        // Skip if needed
-        if tag.starts_with('!') {
-            continue;
-        }
+/// Build token stack from HTML code, returns errors encountered in source order
+fn build_stack(stack: &mut Stack, html: &str) -> Vec<ParseError> {
+    let mut tokenizer = Tokenizer::new(html);
 
-        // Get attr string, if needed
-        let mut attr_string = "";
-        if let Some(cindex) = tag.find(' ') {
-            attr_string = tag[cindex + 1..].trim();
-            tag = &tag[..cindex];
-        }
+    // Go through tokens
+    while let Some(token) = tokenizer.next() {
+        match token {
+            RawToken::StartTag { name, attributes, duplicates, self_closing, span, .. } => {
+                let tag_id = stack.push_element(&name, attributes, duplicates, &self_closing, &html[span]);
 
-        // Process tag
-        if is_closing {
-            stack.close_tag(tag, tag_string);
-        } else {
-            stack.push(tag, attr_string, &is_single, tag_string);
+                // Trailing solidus is ignored outside of foreign content, so element still has raw text contents
+                if self_closing && !stack.get(&tag_id).unwrap().is_self_closing() {
+                    tokenizer.open_element(&name);
+                }
+            }
+            RawToken::EndTag { name, span } => {
+                stack.close_tag(&name, &html[span]);
+            }
+            RawToken::Comment { span } => {
+                stack.push_node(NodeKind::Comment, &html[span]);
+            }
+            RawToken::Doctype { span } => {
+                stack.push_node(NodeKind::Doctype, &html[span]);
+            }
+            RawToken::CData { span } => {
+                stack.push_node(NodeKind::CData, &html[span]);
+            }
+            RawToken::ProcessingInstruction { span } => {
+                stack.push_node(NodeKind::ProcessingInstruction, &html[span]);
+            }
+            RawToken::Text { span } => {
+                stack.push_text(&html[span]);
+            }
         }
     }
 
-    stack
+    // Get errors from both tokenizer and tree construction
+    stack.end_of_file();
+    let mut errors = tokenizer.take_errors();
+    errors.extend(stack.take_errors());
+    errors.sort_by_key(|error| error.span().start);
+    errors
 }
 
-/// Parse string into hashmap of attributes
+/// Parse string into attribute names and values, with character references decoded.  The first occurrence of a
+/// duplicate name is kept.  Attributes are no longer dropped into extra text, so that is always blank.
 pub fn parse_attr(attr_string: &str) -> (HashMap<String, String>, String) {
-    // Initialize
-    let mut attr = HashMap::new();
-    let mut attr_extra: String = attr_string.to_string();
-
-    // Pares attributes
-    let re = Regex::new(r#"([a-zA-Z0-9_\-]+?)=(.+?)(\"|\'|#)"#).unwrap();
-    for cap in re.captures_iter(attr_string) {
-        let key = cap.get(1).unwrap().as_str().trim();
-        let value = cap
-            .get(2)
-            .unwrap()
-            .as_str()
-            .trim_start_matches('\'')
-            .trim_start_matches('"')
-            .trim();
-        attr.insert(key.to_string(), value.to_string());
-        attr_extra = attr_extra.replace(cap.get(0).unwrap().as_str(), "");
+    let (attributes, _) = parse_attributes(attr_string);
+    let attr = attributes.iter().map(|attr| (attr.name(), attr.value())).collect();
+    (attr, String::new())
+}
+
+/// Parse string into ordered attributes, along with names of any duplicate attributes
+pub fn parse_attributes(attr_string: &str) -> (Vec<Attribute>, Vec<String>) {
+    let scan = scan_attributes(attr_string, 0);
+
+    // Get names of duplicate attributes
+    let duplicates = scan
+        .duplicates
+        .iter()
+        .map(|span| attr_string[span.clone()].to_ascii_lowercase())
+        .collect();
+
+    (scan.attributes, duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_html, parse_html_with_diagnostics, ParseError, ParseErrorKind, Span};
+
+    #[test]
+    fn diagnostics_are_in_source_order() {
+        let html = "<div a=1 a=2><span>x</div><p>&bogus; <!-- open";
+        let (stack, errors) = parse_html_with_diagnostics(html);
+        let kinds: Vec<(ParseErrorKind, usize)> = errors.iter().map(|error| (error.kind(), error.span().start)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (ParseErrorKind::DuplicateAttribute("a".to_string()), 9),
+                (ParseErrorKind::MissingEndTag("span".to_string()), 13),
+                (ParseErrorKind::InvalidCharacterReference("&bogus;".to_string()), 29),
+                (ParseErrorKind::EofInComment, 37),
+            ]
+        );
+        assert_eq!(stack.render(), html);
+        assert_eq!(parse_html(html).render(), stack.render());
     }
 
-    (attr, attr_extra.trim().to_string())
+    #[test]
+    fn well_formed_documents_have_no_diagnostics() {
+        let html = "<!DOCTYPE html><html><head><title>a &amp; b</title></head><body><ul><li>one<li>two</ul><br></body></html>";
+        assert!(parse_html_with_diagnostics(html).1.is_empty());
+    }
+
+    #[test]
+    fn unclosed_elements_at_eof() {
+        let (_, errors) = parse_html_with_diagnostics("<div><b>x<p>y");
+        let kinds: Vec<ParseErrorKind> = errors.iter().map(|error| error.kind()).collect();
+        assert_eq!(kinds, [ParseErrorKind::MissingEndTag("div".to_string()), ParseErrorKind::MissingEndTag("b".to_string())]);
+    }
+
+    #[test]
+    fn display_includes_kind_and_span() {
+        let error = ParseError::new(ParseErrorKind::UnexpectedEndTag("p".to_string()), &Span::new(4, 8));
+        assert_eq!(error.to_string(), "unexpected end tag </p> at bytes 4..8");
+        assert_eq!(ParseErrorKind::EofInTag.to_string(), "end of file in tag");
+    }
 }
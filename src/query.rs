@@ -1,15 +1,24 @@
+use crate::cursor::Cursor;
+use crate::elements::{is_raw_text, is_rcdata};
+use crate::entities::decode;
+use crate::error::SelectorError;
+use crate::selector::{Selector, SiblingIndex};
 use crate::stack::Stack;
-use crate::token::Token;
+use crate::token::{NodeKind, Token};
 use crate::token_iter::TokenIter;
 
+/// Read-only search over a stack, any number of queries may run over the same `&Stack` at once
 pub struct Query<'a> {
-    stack: &'a mut Stack,
+    stack: &'a Stack,
     criteria: SearchCriteria,
 }
 
 #[derive(Debug, Clone)]
 pub struct SearchCriteria {
     pub parent_id: usize,
+    pub children_only: bool,
+    pub kind: Option<NodeKind>,
+    pub selector: Option<Result<Selector, SelectorError>>,
     pub tag: String,
     pub id: String,
     pub class: String, 
@@ -22,11 +31,14 @@ pub struct SearchCriteria {
     pub excludes: Vec<usize>
 }
 
-impl Query<'_> {
+impl<'a> Query<'a> {
     /// Instantiate new query
-    pub fn new(stack: &mut Stack) -> Query {
+    pub fn new(stack: &'a Stack) -> Self {
         let criteria = SearchCriteria {
             parent_id: 0,
+            children_only: false,
+            kind: None,
+            selector: None,
             tag: String::new(),
             id: String::new(),
             class: String::new(),
@@ -48,6 +60,30 @@ impl Query<'_> {
         self
     }
 
+    /// Only match direct children of parent id, instead of all descendants
+    pub fn children_only(mut self) -> Self {
+        self.criteria.children_only = true;
+        self
+    }
+
+    /// Search by kind of node (eg. elements, text, comments)
+    pub fn kind(mut self, kind: NodeKind) -> Self {
+        self.criteria.kind = Some(kind);
+        self
+    }
+
+    /// Search by CSS selector, an invalid selector matches nothing
+    pub fn select(mut self, selector: &str) -> Self {
+        self.criteria.selector = Some(Selector::parse(selector));
+        self
+    }
+
+    /// Search by pre-compiled CSS selector
+    pub fn selector(mut self, selector: &Selector) -> Self {
+        self.criteria.selector = Some(Ok(selector.clone()));
+        self
+    }
+
     // Search by tag name
     pub fn tag(mut self, tag: &str) -> Self {
         self.criteria.tag = tag.to_string();
@@ -94,61 +130,146 @@ impl Query<'_> {
 
     /// Add exclude
     pub fn exclude(mut self, token_id: usize) -> Self {
-        self.criteria.excludes.push(token_id.clone());
+        self.criteria.excludes.push(token_id);
         self
     }
 
     /// Set excludes
-    pub fn excludes(mut self, token_ids: &Vec<usize>) -> Self {
-        self.criteria.excludes = token_ids.clone();
+    pub fn excludes(mut self, token_ids: &[usize]) -> Self {
+        self.criteria.excludes = token_ids.to_vec();
         self
     }
 
-    /// Apply search criteria and iterate over matching tokens
-    pub fn iter(mut self) -> TokenIter {
-        let tokens = self.search();
-        TokenIter::new(&tokens)
+    /// Apply search criteria and lazily iterate over matching tokens, the tree is only walked as far as needed
+    pub fn iter(self) -> TokenIter<'a> {
+        TokenIter::new(self.stack, self.criteria)
     }
 
     /// Search tag stack with criteria, return vector of tokens
-    pub fn to_vec(mut self) -> Vec<Token> {
-        self.search()
-    }
-
-    /// Search all tokens and apply criteria
-    fn search(&mut self) -> Vec<Token> {
-        // Initialize
-        self.stack.set_parent_position(&self.criteria.parent_id);
-        self.stack.set_excludes(&self.criteria.excludes);
-        let crit = &self.criteria;
-        let mut tokens: Vec<Token> = Vec::new();
-
-        // Go through items
-        while let Some(token) = self.stack.pull() {
-            if ((!crit.tag.is_empty()) && token.tag() != crit.tag)
-                || ((!crit.id.is_empty()) && !token.attr_equals("id", &crit.id))
-                || ((!crit.class.is_empty()) && !token.attr_has_segment("class", &crit.class))
-                || ((!crit.attr_key.is_empty())
-                    && (!token.attr_equals(&crit.attr_key, &crit.attr_value)))
-                || ((!crit.attr_contains_key.is_empty())
-                    && (!token.attr_contains(&crit.attr_contains_key, &crit.attr_contains_value)))
-                || ((!crit.contents.is_empty()) && token.contents() != crit.contents)
-                || ((!crit.contents_contains.is_empty())
-                    && !token.contents().contains(&crit.contents_contains))
-            {
-                continue;
-            }
-
-            let contents = self.stack.render_tag(&token.id());
-            if !contents.is_empty() {
-                let mtoken = self.stack.get_mut(&token.id()).unwrap();
-                mtoken.set_contents(contents.as_str());
-                tokens.push(mtoken.clone());
-            } else {
-                tokens.push(token);
-            }
+    pub fn to_vec(self) -> Vec<&'a Token> {
+        self.iter().collect()
+    }
+
+    /// Search tag stack with criteria, return ids of matching tokens.  Pass these to `Stack::get_many_mut()` to modify the matches.
+    pub fn ids(self) -> Vec<usize> {
+        self.iter().map(|token| token.id()).collect()
+    }
+}
+
+impl SearchCriteria {
+    /// Get cursor to walk the tokens that may match, children only does not descend past the parent
+    pub fn cursor(&self) -> Cursor {
+        let cursor = Cursor::new(&self.parent_id).excludes(&self.excludes);
+        if self.children_only {
+            return cursor.children_only();
         }
+        cursor
+    }
 
-        tokens
+    /// Check if token matches criteria
+    pub fn matches(&self, stack: &Stack, token: &Token) -> bool {
+        self.matches_with(stack, token, &SiblingIndex::default())
     }
+
+    /// Check if token matches criteria, reusing element positions computed for earlier tokens of the same search
+    pub(crate) fn matches_with(&self, stack: &Stack, token: &Token, siblings: &SiblingIndex) -> bool {
+        if (self.children_only && token.parent_id() != self.parent_id)
+            || (self.kind.is_some() && Some(token.kind()) != self.kind)
+            || ((!self.tag.is_empty()) && token.tag() != self.tag)
+            || ((!self.id.is_empty()) && !token.attr_equals("id", &self.id))
+            || ((!self.class.is_empty()) && !token.attr_has_segment("class", &self.class))
+            || ((!self.attr_key.is_empty())
+                && (!token.attr_equals(&self.attr_key, &self.attr_value)))
+            || ((!self.attr_contains_key.is_empty())
+                && (!token.attr_contains(&self.attr_contains_key, &self.attr_contains_value)))
+            || ((!self.contents.is_empty()) && token_contents(stack, token) != self.contents)
+            || ((!self.contents_contains.is_empty())
+                && !token_contents(stack, token).contains(&self.contents_contains))
+        {
+            return false;
+        }
+
+        // Check selector
+        match &self.selector {
+            Some(Ok(selector)) => selector.matches_with(stack, &token.id(), siblings),
+            Some(Err(_)) => false,
+            None => true,
+        }
+    }
+}
+
+/// Get contents of token to match against, elements are rendered from the stack as their children may have changed
+fn token_contents(stack: &Stack, token: &Token) -> String {
+    if !token.is_element() || is_raw_text(&token.tag()) || is_rcdata(&token.tag()) {
+        return token.contents();
+    }
+    decode(stack.render_tag(&token.id()).trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Query;
+    use crate::{parse_html, NodeKind, Selector};
+
+    const DOCUMENT: &str = "<div id=a class=\"x y\"><p title=\"Hello there\">one &amp; two</p><p>three</p></div>\
+        <div id=b><span>four</span><!--five--></div>";
+
+    /// Get tags of query results, or contents of other nodes
+    fn names(query: Query<'_>) -> Vec<String> {
+        query
+            .iter()
+            .map(|token| if token.is_element() { token.tag() } else { token.contents() })
+            .collect()
+    }
+
+    #[test]
+    fn criteria() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(names(stack.query().tag("p")), ["p", "p"]);
+        assert_eq!(stack.query().id("b").to_vec()[0].tag(), "div");
+        assert_eq!(stack.query().class("y").ids(), stack.query().id("a").ids());
+        assert_eq!(stack.query().class("x y").ids().len(), 0);
+        assert_eq!(stack.query().attr("title", "Hello there").ids().len(), 1);
+        assert_eq!(stack.query().attr_contains("title", "there").ids().len(), 1);
+        assert_eq!(names(stack.query().contents("one & two")), ["p", "one & two"]);
+        assert_eq!(names(stack.query().tag("div").contents_contains("four")), ["div"]);
+        assert_eq!(stack.query().kind(NodeKind::Comment).to_vec()[0].contents(), "<!--five-->");
+        assert_eq!(stack.query().select("div > p:last-child").to_vec()[0].id(), stack.query().tag("p").ids()[1]);
+        assert_eq!(stack.query().selector(&Selector::parse("#b span").unwrap()).ids().len(), 1);
+        assert!(stack.query().select("div >").ids().is_empty());
+    }
+
+    #[test]
+    fn parent_children_and_excludes() {
+        let stack = parse_html(DOCUMENT);
+        let a = stack.query().id("a").ids()[0];
+        let b = stack.query().id("b").ids()[0];
+        assert_eq!(stack.query().parent_id(&a).ids().len(), 4);
+        assert_eq!(names(stack.query().parent_id(&a).children_only()), ["p", "p"]);
+        assert_eq!(names(stack.query().parent_id(&0).children_only()), ["div", "div"]);
+        assert_eq!(stack.query().parent_id(&b).tag("p").ids().len(), 0);
+
+        let first = stack.query().tag("p").ids()[0];
+        assert_eq!(stack.query().tag("p").exclude(first).ids().len(), 1);
+        assert_eq!(stack.query().excludes(&[a]).kind(NodeKind::Element).ids().len(), 2);
+    }
+
+    #[test]
+    fn queries_share_the_stack() {
+        let mut stack = parse_html(DOCUMENT);
+        let first = stack.pull().unwrap().id();
+        {
+            let stack = &stack;
+            let mut outer = stack.query().tag("div").iter();
+            let mut inner = stack.query().tag("p").iter();
+            assert_eq!(outer.next().unwrap().attr("id"), Some("a".to_string()));
+            assert_eq!(inner.next().unwrap().tag(), "p");
+            assert_eq!(outer.next().unwrap().attr("id"), Some("b".to_string()));
+            assert_eq!(inner.count(), 1);
+        }
+
+        // Queries do not move the pull position
+        assert_eq!(stack.pull().unwrap().parent_id(), first);
+    }
+
 }
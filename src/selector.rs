@@ -0,0 +1,936 @@
+use crate::error::SelectorError;
+use crate::stack::Stack;
+use crate::token::{NodeKind, Token};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::rc::Rc;
+
+/// Parsed CSS selector list, eg. `div.card > ul li:nth-child(2n+1), a[href^='https']`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    complex: Vec<Complex>,
+}
+
+/// Compound selectors joined by combinators, eg. `div.card > ul li`
+#[derive(Debug, Clone, PartialEq)]
+struct Complex {
+    compounds: Vec<Compound>,
+    combinators: Vec<Combinator>,
+}
+
+/// Relative selector used within `:has()`, eg. `> img`
+#[derive(Debug, Clone, PartialEq)]
+struct Relative {
+    combinator: Combinator,
+    complex: Complex,
+}
+
+/// Simple selectors that must all match a single element, eg. `a.external[href]`
+#[derive(Debug, Clone, PartialEq)]
+struct Compound {
+    simple: Vec<Simple>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+    Adjacent,
+    Sibling,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Simple {
+    Type(String),
+    Universal,
+    Id(String),
+    Class(String),
+    Attr {
+        name: String,
+        op: AttrOp,
+        value: String,
+        ignore_case: bool,
+    },
+    Pseudo(Pseudo),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AttrOp {
+    Exists,
+    Equals,
+    Includes,
+    DashMatch,
+    Prefix,
+    Suffix,
+    Substring,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pseudo {
+    Not(Selector),
+    Is(Selector),
+    Has(Vec<Relative>),
+    Nth {
+        nth: Nth,
+        of_type: bool,
+        from_end: bool,
+        // Selector of `:nth-child(An+B of S)`, with an index unique within the selector list for caching its matches
+        of: Option<(usize, Selector)>,
+    },
+    Empty,
+    Root,
+}
+
+/// An+B notation of the `:nth-*` pseudo-classes
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Nth {
+    a: i64,
+    b: i64,
+}
+
+impl Nth {
+    /// Check if 1-based position matches
+    fn matches(&self, pos: i64) -> bool {
+        if self.a == 0 {
+            return pos == self.b;
+        }
+        let n = pos - self.b;
+        n % self.a == 0 && n / self.a >= 0
+    }
+}
+
+impl Selector {
+    /// Parse selector list once, so it can be matched against any number of stacks and tokens
+    pub fn parse(selector: &str) -> Result<Self, SelectorError> {
+        let mut parser = Parser { input: selector, pos: 0, nth_of: 0 };
+        let res = parser.selector_list().map_err(|pos| SelectorError::new(selector, &pos))?;
+        parser.skip_whitespace();
+        if parser.pos < selector.len() {
+            return Err(SelectorError::new(selector, &parser.pos));
+        }
+        Ok(res)
+    }
+
+    /// Check if element token within stack matches any selector within the list
+    pub fn matches(&self, stack: &Stack, token_id: &usize) -> bool {
+        self.matches_in(&Context { stack, siblings: &SiblingIndex::default() }, *token_id)
+    }
+
+    /// Check if element token matches, reusing element positions already computed by the query
+    pub(crate) fn matches_with(&self, stack: &Stack, token_id: &usize, siblings: &SiblingIndex) -> bool {
+        self.matches_in(&Context { stack, siblings }, *token_id)
+    }
+
+    /// Check if element token matches any selector within the list
+    fn matches_in(&self, cx: &Context, token_id: usize) -> bool {
+        match cx.stack.tokens.get(&token_id) {
+            Some(token) if token.kind() == NodeKind::Element => {}
+            _ => return false,
+        }
+        self.complex.iter().any(|complex| complex.matches_at(cx, token_id, complex.compounds.len() - 1, None))
+    }
+}
+
+/// Stack being matched against, along with the element positions computed so far
+#[derive(Clone, Copy)]
+struct Context<'a> {
+    stack: &'a Stack,
+    siblings: &'a SiblingIndex,
+}
+
+impl Context<'_> {
+    /// Get element siblings of token along with its position amongst them, None if it is not amongst its parent's children
+    fn siblings_of(&self, token_id: usize) -> Option<(Rc<Siblings>, usize)> {
+        let parent_id = self.stack.tokens.get(&token_id)?.parent_id();
+        let siblings = self.siblings.get(self.stack, parent_id);
+        let pos = siblings.positions.get(&token_id)?.0;
+        Some((siblings, pos))
+    }
+}
+
+/// Element children of parents, computed once per parent and kept for the length of a query so positional
+/// pseudo-classes and sibling combinators do not rescan the parent's children for every element
+#[derive(Debug, Default)]
+pub(crate) struct SiblingIndex {
+    parents: RefCell<HashMap<usize, Rc<Siblings>>>,
+    filtered: RefCell<HashMap<(usize, usize), Rc<Siblings>>>,
+}
+
+/// Element children of a parent, with the position of each amongst all of them and amongst those with the same tag
+#[derive(Debug)]
+struct Siblings {
+    elements: Vec<usize>,
+    positions: HashMap<usize, (usize, usize)>,
+    type_counts: HashMap<String, usize>,
+}
+
+impl SiblingIndex {
+    /// Get element children of parent, or of the document if parent id is 0
+    fn get(&self, stack: &Stack, parent_id: usize) -> Rc<Siblings> {
+        if let Some(siblings) = self.parents.borrow().get(&parent_id) {
+            return siblings.clone();
+        }
+
+        let siblings = Rc::new(Siblings::new(stack, element_children(stack, parent_id)));
+        self.parents.borrow_mut().insert(parent_id, siblings.clone());
+        siblings
+    }
+
+    /// Get element children of parent that match the selector of `:nth-child(An+B of S)`, identified by its index
+    fn get_matching(&self, cx: &Context, parent_id: usize, index: usize, selector: &Selector) -> Rc<Siblings> {
+        let key = (parent_id, index);
+        if let Some(siblings) = self.filtered.borrow().get(&key) {
+            return siblings.clone();
+        }
+
+        let mut elements = self.get(cx.stack, parent_id).elements.clone();
+        elements.retain(|id| selector.matches_in(cx, *id));
+        let siblings = Rc::new(Siblings::new(cx.stack, elements));
+        self.filtered.borrow_mut().insert(key, siblings.clone());
+        siblings
+    }
+}
+
+impl Siblings {
+    /// Index positions of elements
+    fn new(stack: &Stack, elements: Vec<usize>) -> Self {
+        let mut positions = HashMap::new();
+        let mut type_counts: HashMap<String, usize> = HashMap::new();
+        for (pos, id) in elements.iter().enumerate() {
+            let count = type_counts.entry(stack.tokens.get(id).unwrap().tag()).or_default();
+            positions.insert(*id, (pos, *count));
+            *count += 1;
+        }
+        Self { elements, positions, type_counts }
+    }
+}
+
+impl Complex {
+    /// Check if compound at index matches element, along with all compounds to its left.
+    /// Anchor is the element a relative selector within `:has()` is relative to.
+    fn matches_at(&self, cx: &Context, token_id: usize, index: usize, anchor: Option<(usize, Combinator)>) -> bool {
+        if !self.compounds[index].matches(cx, token_id) {
+            return false;
+        } else if index == 0 {
+            return match anchor {
+                Some((anchor_id, combinator)) => is_related(cx, anchor_id, token_id, combinator),
+                None => true,
+            };
+        }
+
+        match self.combinators[index - 1] {
+            Combinator::Descendant => {
+                let mut parent = parent_element(cx.stack, token_id);
+                while let Some(parent_id) = parent {
+                    if self.matches_at(cx, parent_id, index - 1, anchor) {
+                        return true;
+                    }
+                    parent = parent_element(cx.stack, parent_id);
+                }
+                false
+            }
+            Combinator::Child => match parent_element(cx.stack, token_id) {
+                Some(parent_id) => self.matches_at(cx, parent_id, index - 1, anchor),
+                None => false,
+            },
+            Combinator::Adjacent => {
+                let Some((siblings, pos)) = cx.siblings_of(token_id) else { return false };
+                pos > 0 && self.matches_at(cx, siblings.elements[pos - 1], index - 1, anchor)
+            }
+            Combinator::Sibling => {
+                let Some((siblings, pos)) = cx.siblings_of(token_id) else { return false };
+                siblings.elements[..pos].iter().rev().any(|id| self.matches_at(cx, *id, index - 1, anchor))
+            }
+        }
+    }
+}
+
+impl Compound {
+    /// Check if all simple selectors match element
+    fn matches(&self, cx: &Context, token_id: usize) -> bool {
+        let token = cx.stack.tokens.get(&token_id).unwrap();
+        self.simple.iter().all(|simple| simple.matches(cx, token))
+    }
+}
+
+impl Simple {
+    /// Check if simple selector matches element
+    fn matches(&self, cx: &Context, token: &Token) -> bool {
+        match self {
+            Simple::Type(tag) => token.tag().eq_ignore_ascii_case(tag),
+            Simple::Universal => true,
+            Simple::Id(id) => token.attr_equals("id", id),
+            Simple::Class(class) => token.attr_has_segment("class", class),
+            Simple::Attr { name, op, value, ignore_case } => {
+                let actual = match token.attr(name) {
+                    Some(r) => r,
+                    None => return false,
+                };
+                let (actual, value) = if *ignore_case {
+                    (actual.to_lowercase(), value.to_lowercase())
+                } else {
+                    (actual, value.clone())
+                };
+                match op {
+                    AttrOp::Exists => true,
+                    AttrOp::Equals => actual == value,
+                    AttrOp::Includes => actual.split_ascii_whitespace().any(|word| word == value),
+                    AttrOp::DashMatch => actual == value || actual.starts_with(&format!("{}-", value)),
+                    AttrOp::Prefix => !value.is_empty() && actual.starts_with(&value),
+                    AttrOp::Suffix => !value.is_empty() && actual.ends_with(&value),
+                    AttrOp::Substring => !value.is_empty() && actual.contains(&value),
+                }
+            }
+            Simple::Pseudo(pseudo) => pseudo.matches(cx, token),
+        }
+    }
+}
+
+impl Pseudo {
+    /// Check if pseudo-class matches element
+    fn matches(&self, cx: &Context, token: &Token) -> bool {
+        let token_id = token.id();
+        match self {
+            Pseudo::Not(selector) => !selector.matches_in(cx, token_id),
+            Pseudo::Is(selector) => selector.matches_in(cx, token_id),
+            Pseudo::Has(relative) => relative.iter().any(|rel| {
+                has_candidates(cx, token_id, rel.combinator)
+                    .iter()
+                    .any(|id| rel.complex.matches_at(cx, *id, rel.complex.compounds.len() - 1, Some((token_id, rel.combinator))))
+            }),
+            Pseudo::Nth { nth, of_type, from_end, of } => {
+                let Some((mut siblings, mut pos)) = cx.siblings_of(token_id) else { return false };
+
+                // Position is amongst siblings matching the selector only
+                if let Some((index, selector)) = of {
+                    siblings = cx.siblings.get_matching(cx, token.parent_id(), *index, selector);
+                    pos = match siblings.positions.get(&token_id) {
+                        Some(r) => r.0,
+                        None => return false,
+                    };
+                }
+
+                let (pos, count) = if *of_type {
+                    (siblings.positions[&token_id].1, siblings.type_counts[&token.tag()])
+                } else {
+                    (pos, siblings.elements.len())
+                };
+                let pos = if *from_end { count - pos } else { pos + 1 };
+                nth.matches(pos as i64)
+            }
+            Pseudo::Empty => {
+                let has_children = cx.stack.child_ids(&token_id).iter().any(|id| {
+                    let child = cx.stack.tokens.get(id).unwrap();
+                    match child.kind() {
+                        NodeKind::Element | NodeKind::CData => true,
+                        NodeKind::Text => !child.raw_contents().is_empty(),
+                        _ => false,
+                    }
+                });
+                !has_children && token.raw_contents().is_empty()
+            }
+            Pseudo::Root => token.parent_id() == 0,
+        }
+    }
+}
+
+/// Get parent element
+fn parent_element(stack: &Stack, token_id: usize) -> Option<usize> {
+    match stack.tokens.get(&token_id)?.parent_id() {
+        0 => None,
+        parent_id => Some(parent_id),
+    }
+}
+
+/// Get element children of token, or of the document if token id is 0
+fn element_children(stack: &Stack, token_id: usize) -> Vec<usize> {
+    let mut children = stack.child_ids(&token_id).to_vec();
+    children.retain(|id| stack.tokens.get(id).unwrap().kind() == NodeKind::Element);
+    children
+}
+
+/// Get all element descendants of token, in document order
+fn element_descendants(stack: &Stack, token_id: usize) -> Vec<usize> {
+    let mut res = Vec::new();
+    for child_id in element_children(stack, token_id) {
+        res.push(child_id);
+        res.extend(element_descendants(stack, child_id));
+    }
+    res
+}
+
+/// Get elements that a relative selector may match, relative to the anchor element
+fn has_candidates(cx: &Context, anchor_id: usize, combinator: Combinator) -> Vec<usize> {
+    match combinator {
+        Combinator::Descendant | Combinator::Child => element_descendants(cx.stack, anchor_id),
+        Combinator::Adjacent | Combinator::Sibling => {
+            let Some((siblings, pos)) = cx.siblings_of(anchor_id) else { return Vec::new() };
+            let mut res = Vec::new();
+            for sibling_id in siblings.elements[pos + 1..].iter() {
+                res.push(*sibling_id);
+                res.extend(element_descendants(cx.stack, *sibling_id));
+            }
+            res
+        }
+    }
+}
+
+/// Check if element is related to the anchor element by combinator
+fn is_related(cx: &Context, anchor_id: usize, token_id: usize, combinator: Combinator) -> bool {
+    match combinator {
+        Combinator::Descendant => {
+            let mut parent = parent_element(cx.stack, token_id);
+            while let Some(parent_id) = parent {
+                if parent_id == anchor_id {
+                    return true;
+                }
+                parent = parent_element(cx.stack, parent_id);
+            }
+            false
+        }
+        Combinator::Child => parent_element(cx.stack, token_id) == Some(anchor_id),
+        Combinator::Adjacent | Combinator::Sibling => {
+            let Some((siblings, pos)) = cx.siblings_of(token_id) else { return false };
+            match combinator {
+                Combinator::Adjacent => pos > 0 && siblings.elements[pos - 1] == anchor_id,
+                _ => siblings.elements[..pos].contains(&anchor_id),
+            }
+        }
+    }
+}
+
+/// Recursive descent parser over selector string
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    nth_of: usize,
+}
+
+impl Parser<'_> {
+    /// Selector list, complex selectors separated by commas
+    fn selector_list(&mut self) -> Result<Selector, usize> {
+        let mut complex = vec![self.complex()?];
+        while self.eat_after_whitespace(',') {
+            complex.push(self.complex()?);
+        }
+        Ok(Selector { complex })
+    }
+
+    /// Relative selector list used within `:has()`
+    fn relative_list(&mut self) -> Result<Vec<Relative>, usize> {
+        let mut res = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let combinator = self.combinator().unwrap_or(Combinator::Descendant);
+            res.push(Relative { combinator, complex: self.complex()? });
+            if !self.eat_after_whitespace(',') {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// Complex selector, compound selectors joined by combinators
+    fn complex(&mut self) -> Result<Complex, usize> {
+        self.skip_whitespace();
+        let mut compounds = vec![self.compound()?];
+        let mut combinators = Vec::new();
+
+        loop {
+            let start = self.pos;
+            let had_whitespace = self.skip_whitespace();
+            let combinator = match self.combinator() {
+                Some(r) => r,
+                None if had_whitespace && self.starts_compound() => Combinator::Descendant,
+                None => {
+                    self.pos = start;
+                    break;
+                }
+            };
+            self.skip_whitespace();
+            combinators.push(combinator);
+            compounds.push(self.compound()?);
+        }
+
+        Ok(Complex { compounds, combinators })
+    }
+
+    /// Explicit combinator, if one is next
+    fn combinator(&mut self) -> Option<Combinator> {
+        let combinator = match self.peek()? {
+            '>' => Combinator::Child,
+            '+' => Combinator::Adjacent,
+            '~' => Combinator::Sibling,
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(combinator)
+    }
+
+    /// Check if next character starts a compound selector
+    fn starts_compound(&self) -> bool {
+        match self.peek() {
+            Some(c) => matches!(c, '*' | '#' | '.' | '[' | ':' | '\\') || is_ident_start(c),
+            None => false,
+        }
+    }
+
+    /// Compound selector, optional type or universal selector followed by any number of simple selectors
+    fn compound(&mut self) -> Result<Compound, usize> {
+        let mut simple = Vec::new();
+        if self.peek() == Some('*') {
+            self.pos += 1;
+            simple.push(Simple::Universal);
+        } else if self.peek().is_some_and(|c| is_ident_start(c) || c == '\\') {
+            simple.push(Simple::Type(self.ident()?.to_ascii_lowercase()));
+        }
+
+        loop {
+            match self.peek() {
+                Some('#') => {
+                    self.pos += 1;
+                    simple.push(Simple::Id(self.ident()?));
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    simple.push(Simple::Class(self.ident()?));
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    simple.push(self.attribute()?);
+                }
+                Some(':') => {
+                    self.pos += 1;
+                    simple.push(Simple::Pseudo(self.pseudo()?));
+                }
+                _ => break,
+            }
+        }
+
+        if simple.is_empty() {
+            return Err(self.pos);
+        }
+        Ok(Compound { simple })
+    }
+
+    /// Attribute selector, after the opening '['
+    fn attribute(&mut self) -> Result<Simple, usize> {
+        self.skip_whitespace();
+        let name = self.ident()?;
+        self.skip_whitespace();
+
+        // Get operator
+        let op = match self.peek() {
+            Some(']') => {
+                self.pos += 1;
+                return Ok(Simple::Attr { name, op: AttrOp::Exists, value: String::new(), ignore_case: false });
+            }
+            Some('=') => AttrOp::Equals,
+            Some('~') => AttrOp::Includes,
+            Some('|') => AttrOp::DashMatch,
+            Some('^') => AttrOp::Prefix,
+            Some('$') => AttrOp::Suffix,
+            Some('*') => AttrOp::Substring,
+            _ => return Err(self.pos),
+        };
+        self.pos += 1;
+        if op != AttrOp::Equals && !self.eat('=') {
+            return Err(self.pos);
+        }
+
+        // Get value, and case sensitivity flag
+        self.skip_whitespace();
+        let value = match self.peek() {
+            Some('"') | Some('\'') => self.string()?,
+            _ => self.ident()?,
+        };
+        self.skip_whitespace();
+        let mut ignore_case = false;
+        match self.peek() {
+            Some('i') | Some('I') => {
+                ignore_case = true;
+                self.pos += 1;
+            }
+            Some('s') | Some('S') => self.pos += 1,
+            _ => {}
+        }
+        self.skip_whitespace();
+        if !self.eat(']') {
+            return Err(self.pos);
+        }
+
+        Ok(Simple::Attr { name, op, value, ignore_case })
+    }
+
+    /// Pseudo-class, after the ':'
+    fn pseudo(&mut self) -> Result<Pseudo, usize> {
+        let start = self.pos;
+        let name = self.ident()?.to_ascii_lowercase();
+        let nth = |a, b| Nth { a, b };
+
+        // Pseudo-classes without arguments
+        let simple = match name.as_str() {
+            "first-child" => Some(Pseudo::Nth { nth: nth(0, 1), of_type: false, from_end: false, of: None }),
+            "last-child" => Some(Pseudo::Nth { nth: nth(0, 1), of_type: false, from_end: true, of: None }),
+            "first-of-type" => Some(Pseudo::Nth { nth: nth(0, 1), of_type: true, from_end: false, of: None }),
+            "last-of-type" => Some(Pseudo::Nth { nth: nth(0, 1), of_type: true, from_end: true, of: None }),
+            "only-child" => Some(Pseudo::Is(Selector::parse(":first-child:last-child").unwrap())),
+            "only-of-type" => Some(Pseudo::Is(Selector::parse(":first-of-type:last-of-type").unwrap())),
+            "empty" => Some(Pseudo::Empty),
+            "root" => Some(Pseudo::Root),
+            _ => None,
+        };
+        if let Some(pseudo) = simple {
+            return Ok(pseudo);
+        } else if !self.eat('(') {
+            return Err(start);
+        }
+
+        // Functional pseudo-classes
+        self.skip_whitespace();
+        let pseudo = match name.as_str() {
+            "not" => Pseudo::Not(self.selector_list()?),
+            "is" | "where" | "matches" => Pseudo::Is(self.selector_list()?),
+            "has" => Pseudo::Has(self.relative_list()?),
+            "nth-child" | "nth-last-child" | "nth-of-type" | "nth-last-of-type" => {
+                let value = self.nth()?;
+                let of_type = name.ends_with("of-type");
+                let mut of = None;
+                self.skip_whitespace();
+                if !of_type && self.input[self.pos..].starts_with("of ") {
+                    self.pos += 3;
+                    self.nth_of += 1;
+                    let index = self.nth_of;
+                    of = Some((index, self.selector_list()?));
+                }
+                Pseudo::Nth { nth: value, of_type, from_end: name.contains("last"), of }
+            }
+            _ => return Err(start),
+        };
+        self.skip_whitespace();
+        if !self.eat(')') {
+            return Err(self.pos);
+        }
+
+        Ok(pseudo)
+    }
+
+    /// An+B notation, eg. `odd`, `2n+1`, `-n + 3`, `5`
+    fn nth(&mut self) -> Result<Nth, usize> {
+        let start = self.pos;
+        let end = self.input[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == ' '))
+            .map(|r| start + r)
+            .unwrap_or(self.input.len());
+
+        // Stop before ' of ' within `:nth-child(2n of .item)`
+        let mut text = &self.input[start..end];
+        if let Some(r) = text.find(" of ") {
+            text = &text[..r];
+        }
+        self.pos = start + text.trim_end().len();
+        let value: String = text.chars().filter(|c| *c != ' ').collect::<String>().to_ascii_lowercase();
+
+        let res = match value.as_str() {
+            "odd" => Some(Nth { a: 2, b: 1 }),
+            "even" => Some(Nth { a: 2, b: 0 }),
+            _ => match value.find('n') {
+                Some(index) => {
+                    let a = match &value[..index] {
+                        "" | "+" => Some(1),
+                        "-" => Some(-1),
+                        r => r.parse::<i64>().ok(),
+                    };
+                    let b = match &value[index + 1..] {
+                        "" => Some(0),
+                        r if r.starts_with(['+', '-']) => r.trim_start_matches('+').parse::<i64>().ok(),
+                        _ => None,
+                    };
+                    a.zip(b).map(|(a, b)| Nth { a, b })
+                }
+                None => value.trim_start_matches('+').parse::<i64>().ok().map(|b| Nth { a: 0, b }),
+            },
+        };
+
+        res.ok_or(start)
+    }
+
+    /// Identifier, with any escapes resolved
+    fn ident(&mut self) -> Result<String, usize> {
+        let mut res = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                res.push(self.escape()?);
+            } else if is_ident_char(c) && (!res.is_empty() || is_ident_start(c) || c == '-') {
+                res.push(c);
+                self.pos += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+
+        if res.is_empty() || res == "-" {
+            return Err(self.pos);
+        }
+        Ok(res)
+    }
+
+    /// Quoted string
+    fn string(&mut self) -> Result<String, usize> {
+        let start = self.pos;
+        let quote = self.peek().unwrap();
+        self.pos += 1;
+        let mut res = String::new();
+        while let Some(c) = self.peek() {
+            if c == quote {
+                self.pos += 1;
+                return Ok(res);
+            } else if c == '\\' {
+                res.push(self.escape()?);
+            } else {
+                res.push(c);
+                self.pos += c.len_utf8();
+            }
+        }
+        Err(start)
+    }
+
+    /// Escape sequence starting at backslash, eg. `\:` or `\31 `
+    fn escape(&mut self) -> Result<char, usize> {
+        let start = self.pos;
+        self.pos += 1;
+        let rest = &self.input[self.pos..];
+        let len = rest.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(rest.len()).min(6);
+        if len > 0 {
+            let code = u32::from_str_radix(&rest[..len], 16).unwrap();
+            self.pos += len;
+            if self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+            return Ok(char::from_u32(code).filter(|c| *c != '\0').unwrap_or('\u{fffd}'));
+        }
+
+        match rest.chars().next() {
+            Some(c) if c != '\n' => {
+                self.pos += c.len_utf8();
+                Ok(c)
+            }
+            _ => Err(start),
+        }
+    }
+
+    /// Skip whitespace, returns whether any was skipped
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    /// Consume character if it is next
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            return true;
+        }
+        false
+    }
+
+    /// Consume character if it is next after any whitespace, otherwise leave position as is
+    fn eat_after_whitespace(&mut self, c: char) -> bool {
+        let start = self.pos;
+        self.skip_whitespace();
+        if self.eat(c) {
+            return true;
+        }
+        self.pos = start;
+        false
+    }
+
+    /// Get next character
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        Self::parse(selector)
+    }
+}
+
+/// Check if character may start an identifier
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+/// Check if character may be within an identifier
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || !c.is_ascii()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selector::Selector;
+    use crate::{parse_html, Stack};
+
+    const DOCUMENT: &str = "<html><body><div id=main class=\"a b\" lang=en-US data-x=\"Hello World\">\
+        <h1>t</h1><p class=first>1</p><p>2</p><span></span><p class=\"last x-y\">3 <a href=\"https://x.org/doc.pdf\">l</a></p>\
+        </div><ul><li>a<li>b<li>c</ul><!-- c --></body></html>";
+
+    /// Get tags of matches, along with their text if any
+    fn select(stack: &Stack, selector: &str) -> Vec<String> {
+        stack
+            .select(selector)
+            .iter()
+            .map(|token| match stack.get_text(&token.id()) {
+                text if text.is_empty() => token.tag(),
+                text => format!("{}:{}", token.tag(), text),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simple_and_attribute_selectors() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(select(&stack, "h1"), ["h1:t"]);
+        assert_eq!(select(&stack, "H1, li:first-child"), ["h1:t", "li:a"]);
+        assert_eq!(select(&stack, "#main").len(), 1);
+        assert_eq!(select(&stack, ".first, .x-y"), ["p:1", "p:3 l"]);
+        assert_eq!(select(&stack, "div.a.b[lang]").len(), 1);
+        assert_eq!(select(&stack, "div.a.c").len(), 0);
+        assert_eq!(select(&stack, "*").len(), 13);
+        assert_eq!(select(&stack, "[class=first]"), ["p:1"]);
+        assert_eq!(select(&stack, "[class~=x-y]"), ["p:3 l"]);
+        assert_eq!(select(&stack, "[lang|=en]").len(), 1);
+        assert_eq!(select(&stack, "[href^=https]"), ["a:l"]);
+        assert_eq!(select(&stack, "[href$=\".pdf\"]"), ["a:l"]);
+        assert_eq!(select(&stack, "[data-x*=o\\ W]").len(), 1);
+        assert_eq!(select(&stack, "[data-x='hello world' i]").len(), 1);
+        assert_eq!(select(&stack, "[data-x='hello world']").len(), 0);
+    }
+
+    #[test]
+    fn combinators() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(select(&stack, "body a"), ["a:l"]);
+        assert_eq!(select(&stack, "body > a"), Vec::<String>::new());
+        assert_eq!(select(&stack, "div > p"), ["p:1", "p:2", "p:3 l"]);
+        assert_eq!(select(&stack, "h1 + p"), ["p:1"]);
+        assert_eq!(select(&stack, "span + p"), ["p:3 l"]);
+        assert_eq!(select(&stack, "p.first ~ p"), ["p:2", "p:3 l"]);
+        assert_eq!(select(&stack, "p ~ span"), ["span"]);
+        assert_eq!(select(&stack, "html div>p.last   a"), ["a:l"]);
+        assert_eq!(select(&stack, "li + li + li"), ["li:c"]);
+    }
+
+    #[test]
+    fn pseudo_classes() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(select(&stack, "div > :first-child"), ["h1:t"]);
+        assert_eq!(select(&stack, "div > :last-child"), ["p:3 l"]);
+        assert_eq!(select(&stack, "p:first-of-type, p:last-of-type"), ["p:1", "p:3 l"]);
+        assert_eq!(select(&stack, "p:only-child, a:only-child"), ["a:l"]);
+        assert_eq!(select(&stack, "span:only-of-type, h1:only-of-type"), ["h1:t", "span"]);
+        assert_eq!(select(&stack, "li:nth-child(odd)"), ["li:a", "li:c"]);
+        assert_eq!(select(&stack, "li:nth-child(2n)"), ["li:b"]);
+        assert_eq!(select(&stack, "li:nth-child(-n+2)"), ["li:a", "li:b"]);
+        assert_eq!(select(&stack, "li:nth-last-child(1)"), ["li:c"]);
+        assert_eq!(select(&stack, "p:nth-of-type(2)"), ["p:2"]);
+        assert_eq!(select(&stack, "p:nth-last-of-type(3)"), ["p:1"]);
+        assert_eq!(select(&stack, "div > :nth-child(2 of p)"), ["p:2"]);
+        assert_eq!(select(&stack, ":nth-child(1 of .first), :nth-child(1 of .last)"), ["p:1", "p:3 l"]);
+        assert_eq!(select(&stack, "span:empty, p:empty"), ["span"]);
+        assert_eq!(select(&stack, ":root").len(), 1);
+        assert_eq!(stack.select(":root").iter().next().unwrap().tag(), "html");
+        assert_eq!(select(&stack, "p:not(.first, .last)"), ["p:2"]);
+        assert_eq!(select(&stack, ":is(h1, span) + p"), ["p:1", "p:3 l"]);
+        assert_eq!(select(&stack, "p:where(.first)"), ["p:1"]);
+        assert_eq!(select(&stack, "p:has(a)"), ["p:3 l"]);
+        assert_eq!(select(&stack, "p:has(+ span)"), ["p:2"]);
+        assert_eq!(select(&stack, "div:has(> p.first ~ span)").len(), 1);
+        assert_eq!(select(&stack, "p:has(~ span)"), ["p:1", "p:2"]);
+    }
+
+    #[test]
+    fn selector_error_positions() {
+        let cases = [
+            ("", 0),
+            ("div >", 5),
+            ("p:unknown", 2),
+            ("[a=", 3),
+            ("div,,p", 4),
+            (":nth-child(x)", 11),
+            (".1a", 1),
+            ("p::before", 2),
+            ("div[a=\"b]", 6),
+            (":not()", 5),
+            ("p)", 1),
+        ];
+        for (selector, position) in cases {
+            let error = Selector::parse(selector).unwrap_err();
+            assert_eq!((error.selector(), error.position()), (selector.to_string(), position));
+        }
+        assert_eq!(Selector::parse("div >").unwrap_err().to_string(), "invalid selector 'div >', unexpected end at position 5");
+        assert!(parse_html(DOCUMENT).select("div >").iter().next().is_none());
+    }
+
+    #[test]
+    fn detached_token_matches_no_positional_selector() {
+        let mut stack = parse_html("<div><table><tr><td>1</td></tr></table><p>2</p></div>");
+        let table = stack.query().tag("table").ids()[0];
+        let p = stack.query().tag("p").ids()[0];
+        assert!(stack.detach(&table));
+        assert!(stack.detach(&p));
+
+        assert!(stack.closest(&table, "table:first-child").is_none());
+        for selector in ["table:nth-child(1)", "table:last-of-type", "p + table", "p ~ table", "div > table", "table:has(+ p)"] {
+            let selector = Selector::parse(selector).unwrap();
+            assert!(!stack.get(&table).unwrap().matches(&selector, &stack));
+        }
+        assert!(stack.closest(&table, "table").is_some());
+        assert!(stack.select("td").ids().is_empty());
+    }
+
+    #[test]
+    fn positional_selectors_over_long_sibling_list() {
+        let items: String = (1..=3000).map(|i| format!("<li class=c{}>{}</li> <p>x</p>", i % 3, i)).collect();
+        let stack = parse_html(&format!("<ul>{}</ul>", items));
+        let text = |selector: &str| -> Vec<String> { stack.select(selector).iter().map(|li| stack.get_text(&li.id())).collect() };
+
+        assert_eq!(text("li:first-child"), vec!["1"]);
+        assert_eq!(text("p:last-child").len(), 1);
+        assert_eq!(text("li:nth-child(4n+3)")[..2], ["2", "4"]);
+        assert_eq!(text("li:nth-of-type(1000)"), vec!["1000"]);
+        assert_eq!(text("li:nth-last-of-type(2)"), vec!["2999"]);
+        assert_eq!(text("li:nth-child(2 of .c0)"), vec!["6"]);
+        assert_eq!(text("p + li").len(), 2999);
+        assert_eq!(text("li ~ li").len(), 2999);
+        assert_eq!(text("li:nth-child(2n+1) ~ p:nth-of-type(2999)").len(), 1);
+    }
+
+    #[test]
+    fn compiled_selector_matches_string_selector_across_stacks() {
+        let pages = [
+            "<ul><li>1</li><li class=a>2</li><li>3</li></ul>",
+            "<div><p>x <a href='https://a'>a</a></p><p><a href=/b>b</a></p></div>",
+            "<table><tr><td>1<td>2</tr><tr><td>3</tr></table>",
+            "<section><h2>T</h2><ul><li><a href=https://c>c</a></li></ul></section>",
+        ];
+        let selectors = ["li:nth-child(2n+1)", "p > a[href^='https']", "td:last-child", "h2 + ul a", "li.a ~ li", ":has(> a)"];
+        let compiled: Vec<Selector> = selectors.iter().map(|selector| selector.parse().unwrap()).collect();
+
+        for page in pages {
+            let stack = parse_html(page);
+            for (selector, compiled) in selectors.iter().zip(compiled.iter()) {
+                let ids = stack.select(selector).ids();
+                assert_eq!(stack.select_with(compiled).ids(), ids);
+                let matched: Vec<usize> = stack.iter().filter(|token| token.matches(compiled, &stack)).map(|token| token.id()).collect();
+                assert_eq!(matched, ids);
+            }
+        }
+    }
+}
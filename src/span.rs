@@ -0,0 +1,23 @@
+/// Byte range within the source document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Create new span
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Get length in bytes
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Check if span is empty, as for implied tags which do not appear within the source
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
@@ -1,30 +1,70 @@
+use crate::attribute::Attribute;
+use crate::cursor::Cursor;
+use crate::doctype::Doctype;
+use crate::elements::{is_raw_text, is_rcdata, is_void, OPTIONAL_END_TAGS};
+use crate::entities::{escape_raw_text, escape_text};
+use crate::error::{ParseError, ParseErrorKind, XPathError};
 use crate::query::Query;
-use crate::token::Token;
+use crate::selector::Selector;
+use crate::span::Span;
+use crate::token::{NodeKind, Token};
 use crate::token_iter::TokenIter;
-use regex::{Regex, RegexBuilder};
+use crate::token_mut::TokenMut;
+use crate::xpath::{XPath, XPathValue};
+use crate::parse_attributes;
+use regex::{Captures, Regex, RegexBuilder};
 use std::collections::HashMap;
-use std::iter::repeat;
+use std::sync::LazyLock;
+
+mod mutate;
+mod navigate;
+mod tree;
+
+/// Opening and closing placeholders of tokens within the code, eg. `<parsex12>` and `</parsex12>`
+pub(crate) static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<(/?)parsex(\d+)>").unwrap());
+
+/// How void elements are written when re-serializing modified tokens, eg. `<br>` or `<br />`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderStyle {
+    Html,
+    Xhtml,
+}
+
+/// Where to insert HTML relative to a target element, as per `insertAdjacentHTML`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    BeforeBegin,
+    AfterBegin,
+    BeforeEnd,
+    AfterEnd,
+}
 
 #[derive(Debug, Clone)]
 pub struct Stack {
     tag_id: usize,
-    parent_id: usize,
     depth: HashMap<String, Vec<usize>>,
     pub tokens: HashMap<usize, Token>,
     stack: HashMap<usize, Vec<usize>>,
     code: String,
-    position: usize,
-    parent_position: usize,
-    excludes: Vec<usize>,
+    source: String,
+    cursor: usize,
+    offset: usize,
+    open: Vec<usize>,
+    formatting: Vec<Option<usize>>,
+    walk: Cursor,
+    render_style: RenderStyle,
+    errors: Vec<ParseError>,
+    detached: HashMap<usize, String>,
 }
 
 impl Stack {
     /// Instantiate a new token stack
     pub fn new(code: &str) -> Self {
-        let mut stack = Self::default();
-        stack.code = code.to_owned();
-
-        stack
+        Self {
+            code: code.to_owned(),
+            source: code.to_owned(),
+            ..Default::default()
+        }
     }
 
     /// Push new token onto stack
@@ -34,134 +74,259 @@ impl Stack {
         attr_string: &str,
         is_single: &bool,
         tag_string: &str,
+    ) -> usize {
+        let (attributes, duplicates) = parse_attributes(attr_string);
+        self.push_element(tag, attributes, duplicates, is_single, tag_string)
+    }
+
+    /// Push new token onto stack with attributes already read by the tokenizer
+    pub(crate) fn push_element(
+        &mut self,
+        tag: &str,
+        attributes: Vec<Attribute>,
+        duplicates: Vec<String>,
+        is_single: &bool,
+        tag_string: &str,
+    ) -> usize {
+        // Void elements never have contents, so auto-close them
+        let is_single = &(*is_single || is_void(tag));
+        self.seek(tag_string);
+        self.start_tag(tag, attributes, duplicates, is_single, tag_string)
+    }
+
+    /// Push node other than an element (eg. comment, doctype) onto stack, inserted as is
+    pub fn push_node(&mut self, kind: NodeKind, source: &str) -> usize {
+        self.seek(source);
+        self.insert_node(kind, source)
+    }
+
+    /// Push text node onto stack, none if text is the body of a raw text or RCDATA element (eg. script, title)
+    pub fn push_text(&mut self, text: &str) -> Option<usize> {
+        let tag = self.tokens.get(&self.current_node()).map(|token| token.tag()).unwrap_or_default();
+        if is_raw_text(&tag) || is_rcdata(&tag) {
+            self.skip(text);
+            return None;
+        }
+
+        // Re-open misnested formatting elements the text belongs within
+        self.reconstruct_formatting();
+        Some(self.push_node(NodeKind::Text, text))
+    }
+
+    /// Close a previously opened HTML tag wwithin stack
+    pub fn close_tag(&mut self, tag: &str, tag_string: &str) {
+        self.seek(tag_string);
+        self.end_tag(tag, tag_string);
+    }
+
+    /// Insert new token as last child of the current node
+    fn insert(
+        &mut self,
+        tag: &str,
+        attributes: Vec<Attribute>,
+        duplicates: Vec<String>,
+        is_single: &bool,
+        tag_string: &str,
     ) -> usize {
         self.tag_id += 1;
-        self.stack.entry(self.parent_id).or_default().push(self.tag_id);
+        let parent_id = self.current_node();
+        self.stack.entry(parent_id).or_default().push(self.tag_id);
         if !is_single {
             self.depth.entry(tag.to_string()).or_default().push(self.tag_id);
+            self.open.push(self.tag_id);
         }
 
         // Add token
         let token_depth = if let Some(d) = self.depth.get(tag) { d.len() } else { 0 };
-        let contents = if tag == "!" { tag_string } else { "" };
-        self.tokens.insert(
-            self.tag_id,
-            Token::new(
-                &self.tag_id,
-                &self.parent_id,
-                &token_depth,
-                &is_single,
-                tag,
-                attr_string,
-                contents,
-            ),
+        let mut token = Token::element(
+            &self.tag_id,
+            &parent_id,
+            &token_depth,
+            is_single,
+            tag,
+            attributes,
+            duplicates,
         );
-        self.code = self.code.replacen(tag_string, format!("<parsex{}>", self.tag_id).as_str(), 1);
+        token.set_source(tag_string);
+        let span = self.replace_at_cursor(tag_string, &format!("<parsex{}>", self.tag_id));
+        token.set_span(&span);
+        self.tokens.insert(self.tag_id, token);
 
-        self.parent_id = self.tag_id;
         self.tag_id
     }
 
-    /// Close a previously opened HTML tag wwithin stack
-    pub fn close_tag(&mut self, tag: &str, tag_string: &str) {
-        if !self.depth.contains_key(tag) {
-            return;
+    /// Insert new node other than an element as last child of the current node
+    fn insert_node(&mut self, kind: NodeKind, source: &str) -> usize {
+        self.tag_id += 1;
+        let parent_id = self.current_node();
+        self.stack.entry(parent_id).or_default().push(self.tag_id);
+
+        // Add token
+        let mut token = Token::node(&self.tag_id, &parent_id, kind, source);
+        token.set_source(source);
+        let span = self.replace_at_cursor(source, &format!("<parsex{}>", self.tag_id));
+        token.set_span(&span);
+        self.tokens.insert(self.tag_id, token);
+
+        self.tag_id
+    }
+
+    /// Pop the current node off the open elements, and close it within stack
+    fn pop(&mut self, tag_string: &str) -> usize {
+        let tag_id = self.open.pop().unwrap();
+        let tag = self.tokens.get(&tag_id).unwrap().tag();
+        if let Some(ids) = self.depth.get_mut(&tag) {
+            ids.retain(|id| *id != tag_id);
+            if ids.is_empty() {
+                self.depth.remove(&tag);
+            }
         }
+        let span = self.replace_at_cursor(tag_string, &format!("</parsex{}>", &tag_id));
 
-        let tag_id: usize = self.depth.get_mut(tag).unwrap().pop().unwrap();
-        if self.depth.get(tag).unwrap().is_empty() {
-            self.depth.remove(tag);
+        // Move body of raw text and RCDATA elements onto the token, so text within it that looks like a
+        // placeholder is never rewritten
+        let body = if is_raw_text(&tag) || is_rcdata(&tag) {
+            self.take_body(&tag_id)
+        } else {
+            None
+        };
+
+        // Element written within the source closed without its own end tag
+        let open_span = self.tokens.get(&tag_id).unwrap().span();
+        if tag_string.is_empty() && !open_span.is_empty() && !OPTIONAL_END_TAGS.contains(&tag.as_str()) {
+            self.error(ParseErrorKind::MissingEndTag(tag.clone()), &open_span);
         }
-        self.code = self.code.replacen(tag_string, format!("</parsex{}>", &tag_id).as_str(), 1);
 
         // Update token as necessary
         let token = self.tokens.get_mut(&tag_id).unwrap();
         token.mark_closed();
-        self.parent_id = token.parent_id();
-
-        // Get body
-        //let search = format!("<parsex{}>(.*?)</parsex{}>", tag_id, tag_id);
-        //let re = RegexBuilder::new(&search).dot_matches_new_line(true).build().unwrap();
-        //if let Some(m) = re.captures(&self.code) {
-            //token.set_contents(m.get(1).unwrap().as_str());
-        //}
+        token.set_close_source(tag_string);
+        token.set_close_span(&span);
+        if let Some(contents) = body {
+            token.replace_contents(&contents);
+        }
+        tag_id
     }
 
-    /// Pull the next immutable token off the stack in hierarchial order, top to bottom, left to right
-    pub fn pull(&mut self) -> Option<Token> {
-        // Get next token
-        if let Some(pos) = self.get_next_position() {
-            let token = self.get(&pos).unwrap();
-            return Some(token.clone());
+    /// Remove code between opening placeholder of element and its closing placeholder just placed before the cursor
+    fn take_body(&mut self, tag_id: &usize) -> Option<String> {
+        let start = self.code.find(&format!("<parsex{}>", tag_id))? + format!("<parsex{}>", tag_id).len();
+        let end = self.cursor.checked_sub(format!("</parsex{}>", tag_id).len())?;
+        if end < start {
+            return None;
         }
+        let body: String = self.code.drain(start..end).collect();
+        self.cursor -= body.len();
+        Some(body)
+    }
 
-        None
+    /// Get id of the current node, the most recently opened element still open
+    fn current_node(&self) -> usize {
+        self.open.last().copied().unwrap_or(0)
     }
 
-    /// Pull the next mutable token off the stack in hierarchial order, top to bottom, left to right
-    pub fn pull_mut(&mut self) -> Option<&mut Token> {
-        // Get next token
-        if let Some(pos) = self.get_next_position() {
-            return Some(self.get_mut(&pos).unwrap());
+    /// Move cursor to the next occurrence of tag string
+    fn seek(&mut self, tag_string: &str) {
+        if let Some(r) = self.code[self.cursor..].find(tag_string) {
+            self.cursor += r;
+            self.offset += r;
         }
+    }
 
-        None
+    /// Move cursor past tag string that is being ignored, leaving it within the code as is
+    fn skip(&mut self, tag_string: &str) {
+        if self.code[self.cursor..].starts_with(tag_string) {
+            self.cursor += tag_string.len();
+            self.offset += tag_string.len();
+        }
     }
 
-    /// Retrieve single immutable token
-    pub fn get(&mut self, token_id: &usize) -> Option<Token> {
-        if let Some(token) = self.tokens.get(token_id) {
-            return Some(token.clone());
+    /// Replace next occurrence of tag string after the cursor with a placeholder, and move cursor past it.
+    /// Returns span of the tag string within the source.
+    fn replace_at_cursor(&mut self, tag_string: &str, placeholder: &str) -> Span {
+        let r = match self.code[self.cursor..].find(tag_string) {
+            Some(r) => r,
+            None => return Span::new(self.offset, self.offset),
+        };
+        let start = self.cursor + r;
+        self.code.replace_range(start..start + tag_string.len(), placeholder);
+        self.cursor = start + placeholder.len();
+        self.offset += r + tag_string.len();
+
+        Span::new(self.offset - tag_string.len(), self.offset)
+    }
+
+    /// Record parse error
+    fn error(&mut self, kind: ParseErrorKind, span: &Span) {
+        self.errors.push(ParseError::new(kind, span));
+    }
+
+    /// Take parse errors recorded while building the stack
+    pub(crate) fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Get line and column numbers, both starting at 1, of byte offset within the source
+    pub fn line_col(&self, offset: &usize) -> (usize, usize) {
+        let mut end = (*offset).min(self.source.len());
+        while !self.source.is_char_boundary(end) {
+            end -= 1;
         }
-        None
+
+        let before = &self.source[..end];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|r| r + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count() + 1;
+        (line, column)
     }
 
-    /// Retrieve single mutable token
-    pub fn get_mut(&mut self, token_id: &usize) -> Option<&mut Token> {
-        self.tokens.get_mut(token_id)
+    /// Get original source the stack was parsed from
+    pub fn source(&self) -> String {
+        self.source.clone()
     }
 
-    /// Get next position to pull token from
-    fn get_next_position(&mut self) -> Option<usize> {
-        // Get next position
-        let mut parent_id = self.position;
-        loop {
-            // Get children, and check if we hit bottom of tree.
-            if parent_id == 0 && !self.stack.contains_key(&parent_id) {
-                return None;
-            } else if !self.stack.contains_key(&parent_id) {
-                parent_id = self.tokens.get(&parent_id).unwrap().parent_id();
-            }
-            let mut children = self.stack.get(&parent_id).unwrap().clone();
-            children.sort();
+    /// Pull the next immutable token off the stack in hierarchial order, top to bottom, left to right
+    pub fn pull(&mut self) -> Option<&Token> {
+        let pos = self.next_position()?;
+        self.tokens.get(&pos)
+    }
 
-            // Get next index
-            let mut index = match children.binary_search(&self.position) {
-                Ok(r) => r + 1,
-                Err(_) => 0,
-            };
+    /// Pull the next mutable token off the stack in hierarchial order, top to bottom, left to right
+    pub fn pull_mut(&mut self) -> Option<TokenMut<'_>> {
+        let pos = self.next_position()?;
+        self.get_mut(&pos)
+    }
 
-            // Check excludes
-            if index < children.len() && self.excludes.contains(&children[index]) {
-                index += 1;
-            }
+    /// Retrieve single immutable token
+    pub fn get(&self, token_id: &usize) -> Option<&Token> {
+        self.tokens.get(token_id)
+    }
 
-            // Check if done this set of tokens
-            if index >= children.len()
-                && ((parent_id == 0)
-                    || (self.parent_position > 0 && parent_id == self.parent_position))
-            {
-                return None;
-            } else if index >= children.len() {
-                self.position = parent_id;
-                parent_id = self.tokens.get(&parent_id).unwrap().parent_id();
-                continue;
-            }
-            self.position = children[index];
-            break;
+    /// Retrieve single mutable token, changes to its contents are made to the tree
+    pub fn get_mut(&mut self, token_id: &usize) -> Option<TokenMut<'_>> {
+        if !self.tokens.contains_key(token_id) {
+            return None;
+        }
+        Some(TokenMut::new(self, *token_id))
+    }
+
+    /// Retrieve multiple mutable tokens at once, in the order of the ids given.  Unknown and repeated ids are skipped.
+    pub fn get_many_mut(&mut self, token_ids: &[usize]) -> Vec<&mut Token> {
+        let mut order: HashMap<usize, usize> = HashMap::new();
+        for (index, token_id) in token_ids.iter().enumerate() {
+            order.entry(*token_id).or_insert(index);
         }
+        let mut tokens: Vec<&mut Token> = self.tokens.values_mut().filter(|token| order.contains_key(&token.id())).collect();
+        tokens.sort_by_key(|token| order[&token.id()]);
+        tokens
+    }
 
-        Some(self.position)
+    /// Get next position to pull token from
+    fn next_position(&mut self) -> Option<usize> {
+        let mut walk = std::mem::take(&mut self.walk);
+        let pos = walk.next_id(self);
+        self.walk = walk;
+        pos
     }
 
     /// Save token
@@ -171,57 +336,164 @@ impl Stack {
         }
     }
     /// Iterate over all tokens in stack in hierarchial order, top to bottom, left to right
-    pub fn iter(&mut self) -> TokenIter {
+    pub fn iter(&self) -> TokenIter<'_> {
         Query::new(self).iter()
     }
     // Query tags by desired criteria
-    pub fn query(&mut self) -> Query {
+    pub fn query(&self) -> Query<'_> {
         Query::new(self)
     }
 
     /// Get contents of tag
-    pub fn get_contents(&mut self, token_id: &usize) -> Option<String> {
-
-        // Get body
-        let search = format!(r"(?s)<parsex{}>(.*?)</parsex{}>", token_id, token_id);
-        let re = RegexBuilder::new(&search).dot_matches_new_line(true).build().unwrap();
-        if let Some(m) = re.captures(&self.code) {
-            return Some(m.get(1).unwrap().as_str().to_string());
+    pub fn get_contents(&self, token_id: &usize) -> Option<String> {
+        let open = format!("<parsex{}>", token_id);
+        let start = self.code.find(&open)? + open.len();
+        let end = start + self.code[start..].find(&format!("</parsex{}>", token_id))?;
+
+        // Raw text and RCDATA elements keep their body on the token
+        let token = self.tokens.get(token_id)?;
+        if is_raw_text(&token.tag()) || is_rcdata(&token.tag()) {
+            return Some(token.raw_body());
         }
-        None
+        Some(self.code[start..end].to_string())
     }
 
-    /// Set contents of tag
+    /// Set contents of tag as HTML, same as `set_inner_html()`
     pub fn set_contents(&mut self, token_id: &usize, new_contents: &str) {
+        self.set_inner_html(token_id, new_contents);
+    }
 
-        // Initialize
-        let search = format!(r"(?s)<parsex{}>.*?</parsex{}>", token_id, token_id);
-        let replace_text = format!("<parsex{}>{}</parsex{}>", token_id, new_contents, token_id);
+    /// Get doctype of document, if it has one
+    pub fn doctype(&self) -> Option<Doctype> {
+        self.doctype_id().and_then(|token_id| self.tokens.get(&token_id).unwrap().doctype())
+    }
 
-        // Search and replace
-        let re = RegexBuilder::new(&search).dot_matches_new_line(true).build().unwrap();
-        self.code = re.replace_all(&self.code.clone(), &replace_text).to_string();
+    /// Set doctype of document, replacing the existing one or adding it to the start of the document
+    pub fn set_doctype(&mut self, doctype: &Doctype) {
+        if let Some(token_id) = self.doctype_id() {
+            self.tokens.get_mut(&token_id).unwrap().replace_contents(&doctype.render());
+            return;
+        }
+
+        self.tag_id += 1;
+        let token = Token::node(&self.tag_id, &0, NodeKind::Doctype, &doctype.render());
+        self.tokens.insert(self.tag_id, token);
+        self.stack.entry(0).or_default().insert(0, self.tag_id);
+        self.code.insert_str(0, &format!("<parsex{}>", self.tag_id));
+        self.cursor += format!("<parsex{}>", self.tag_id).len();
     }
 
+    /// Get id of doctype token
+    fn doctype_id(&self) -> Option<usize> {
+        let children = self.stack.get(&0)?;
+        children.iter().copied().find(|id| self.tokens.get(id).unwrap().kind() == NodeKind::Doctype)
+    }
+
+    /// Get text of token and all its descendants, with character references decoded
+    pub fn get_text(&self, token_id: &usize) -> String {
+        let token = match self.tokens.get(token_id) {
+            Some(r) => r,
+            None => return String::new(),
+        };
+
+        // Text nodes, and raw text / RCDATA elements which contain their text directly
+        if token.is_text() || is_raw_text(&token.tag()) || is_rcdata(&token.tag()) {
+            return token.contents();
+        }
+
+        let mut text = String::new();
+        for child_id in self.stack.get(token_id).cloned().unwrap_or_default() {
+            let child = self.tokens.get(&child_id).unwrap();
+            if child.is_text() || child.is_element() {
+                text.push_str(&self.get_text(&child_id));
+            }
+        }
+        text
+    }
+
+    /// Set contents of tag to plain text, escaping it as needed for the element.  Replaces all children with a single text node.
+    pub fn set_text(&mut self, token_id: &usize, text: &str) {
+        let (tag, kind) = match self.tokens.get(token_id) {
+            Some(r) => (r.tag(), r.kind()),
+            None => return,
+        };
+
+        // Raw text elements (eg. script, style) cannot contain references, so only their end tags are escaped
+        let contents = if is_raw_text(&tag) { escape_raw_text(text, &tag) } else { escape_text(text) };
+        if kind == NodeKind::Text {
+            self.tokens.get_mut(token_id).unwrap().replace_contents(&contents);
+            return;
+        } else if kind != NodeKind::Element || self.get_contents(token_id).is_none() {
+            return;
+        } else if is_raw_text(&tag) || is_rcdata(&tag) {
+            self.tokens.get_mut(token_id).unwrap().replace_contents(&contents);
+            return;
+        }
+
+        // Replace children with new text node
+        self.remove_descendants(token_id);
+        self.tag_id += 1;
+        let token = Token::node(&self.tag_id, token_id, NodeKind::Text, &contents);
+        self.tokens.insert(self.tag_id, token);
+        self.stack.insert(*token_id, vec![self.tag_id]);
+        self.set_code_contents(token_id, &format!("<parsex{}>", self.tag_id));
+    }
+
+    /// Remove all descendants of token from the stack
+    fn remove_descendants(&mut self, token_id: &usize) {
+        for child_id in self.stack.remove(token_id).unwrap_or_default() {
+            self.remove_descendants(&child_id);
+            self.tokens.remove(&child_id);
+        }
+    }
+
+    /// Get ids of children of token in document order, or of the document if token id is 0
+    pub(crate) fn child_ids(&self, token_id: &usize) -> &[usize] {
+        self.stack.get(token_id).map(|r| r.as_slice()).unwrap_or_default()
+    }
+
+    /// Select elements matching CSS selector (eg. `div.card > ul li:nth-child(2n+1)`), must call .iter() or .to_vec() on this result
+    pub fn select(&self, selector: &str) -> Query<'_> {
+        self.query().select(selector)
+    }
+
+    /// Select elements matching pre-compiled CSS selector, must call .iter() or .to_vec() on this result
+    pub fn select_with(&self, selector: &Selector) -> Query<'_> {
+        self.query().selector(selector)
+    }
+
+    /// Modify every element matching CSS selector in document order, eg. to change attributes, contents or tag names in one pass.
+    /// Matches removed by changes to an earlier match are skipped.
+    pub fn for_each_match_mut(&mut self, selector: &str, mut f: impl FnMut(&mut TokenMut<'_>)) {
+        for token_id in self.select(selector).ids() {
+            if let Some(mut token) = self.get_mut(&token_id) {
+                f(&mut token);
+            }
+        }
+    }
+
+    /// Evaluate XPath 1.0 expression against document (eg. `//ul/li[2]/a/@href`, `count(//li)`)
+    pub fn xpath(&self, expression: &str) -> Result<XPathValue, XPathError> {
+        Ok(XPath::parse(expression)?.evaluate(self))
+    }
 
     // Get children tokens, must call .iter() or .to_vec() on this result
-    pub fn get_children(&mut self, token_id: &usize) -> Query {
-        self.query().parent_id(token_id)
+    pub fn get_children(&self, token_id: &usize) -> Query<'_> {
+        self.query().parent_id(token_id).children_only()
     }
 
     /// Set parent id for next pull of all tokens
     pub fn set_parent_position(&mut self, parent_id: &usize) {
-        self.position = *parent_id;
-        self.parent_position = *parent_id;
+        self.walk = Cursor::new(parent_id).excludes(self.walk.excluded());
     }
 
-    /// Set excludes
-    pub fn set_excludes(&mut self, excludes: &Vec<usize>) {
-        self.excludes = excludes.clone();
+    /// Set tokens skipped by pull, along with their descendants
+    pub fn set_excludes(&mut self, excludes: &[usize]) {
+        self.walk = self.walk.clone().excludes(excludes);
     }
 
     // Render stack
-    pub fn render(&mut self) -> String {
+    pub fn render(&self) -> String {
         self.render_tag(&0)
     }
 
@@ -234,7 +506,7 @@ impl Stack {
             let attr_string: String = token
                 .attributes()
                 .iter()
-                .map(|(key, value)| format!("{}=\"{}\"", key, value))
+                .map(|attr| attr.render())
                 .collect::<Vec<String>>()
                 .join(" ");
 
@@ -244,17 +516,17 @@ impl Stack {
                 format!(
                     "{}>{}</{}>",
                     open_tag.trim_end(),
-                    token.contents(),
+                    token.raw_contents(),
                     token.tag()
                 )
             } else {
                 format!("{}/>", open_tag)
             };
 
-            // Quick replace if comment or tag not closed
-            if token.tag() == "!" {
+            // Quick replace if not an element or tag not closed
+            if !token.is_element() {
                 let search = format!("<parsex{}>", token.id());
-                html = html.replace(&search, token.contents().as_str());
+                html = html.replace(&search, token.raw_contents().as_str());
                 continue;
             } else if !token.is_closed() {
                 let search = format!("<parsex{}>", token.id());
@@ -274,22 +546,21 @@ impl Stack {
         html
     }
 
-    /// Render tag
-    pub fn render_tag(&mut self, token_id: &usize) -> String {
+    /// Render tag, unmodified tokens are written exactly as they appeared within the source
+    pub fn render_tag(&self, token_id: &usize) -> String {
 
-        // Get contents
-        let mut html = if *token_id == 0 {
-            self.code.clone()
-        } else {
-            self.get_contents(&token_id).unwrap_or("".to_string())
+        // Get contents, raw text and RCDATA elements hold no placeholders
+        let html = match self.tokens.get(token_id) {
+            Some(token) if is_raw_text(&token.tag()) || is_rcdata(&token.tag()) => return token.raw_body(),
+            _ if *token_id == 0 => self.code.clone(),
+            _ => self.get_contents(token_id).unwrap_or("".to_string()),
         };
         if html.is_empty() {
             return html;
         }
 
         // Go through tokenized tags
-        let re = Regex::new(r"<([\/]?)parsex(\d+?)>").unwrap();
-        for cap in re.captures_iter(&html.clone()) {
+        PLACEHOLDER.replace_all(&html, |cap: &Captures| {
 
             // Set variables
             let is_closing: bool = cap.get(1).unwrap().as_str() == "/";
@@ -298,51 +569,84 @@ impl Stack {
             // Get token
             let token = match self.tokens.get(&token_id) {
                 Some(r) => r,
-                None => continue
+                None => return cap.get(0).unwrap().as_str().to_string()
             };
 
-            // Get attribute string
-            let attr_string: String = token.attributes().iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect::<Vec<String>>().join(" ");
-            let single_slash = if !token.is_closed() { "/" } else { "" };
-        let tag_string = format!("{} {} {}", attr_string, token.attr_extra(), single_slash);
+            if is_closing && (is_raw_text(&token.tag()) || is_rcdata(&token.tag())) {
+                format!("{}{}", token.raw_body(), self.close_tag_html(token))
+            } else if is_closing {
+                self.close_tag_html(token)
+            } else if token.is_dirty() {
+                self.open_tag(token)
+            } else {
+                token.source()
+            }
+        })
+        .to_string()
+    }
 
-            let open_tag = format!("<{} {}>", token.tag(), tag_string.trim());
-            html = html.replace(format!("<parsex{}>", token.id()).as_str(), open_tag.as_str());
+    /// Get closing tag of token as HTML
+    fn close_tag_html(&self, token: &Token) -> String {
+        if !token.is_dirty() {
+            token.close_source()
+        } else if token.is_void() || token.is_self_closing() {
+            String::new()
+        } else {
+            format!("</{}>", token.tag())
+        }
+    }
+
+    /// Get opening tag of token as HTML
+    fn open_tag(&self, token: &Token) -> String {
+        if !token.is_element() {
+            return token.raw_contents();
+        }
+
+        // Get tag name, attributes and extra text
+        let mut parts = vec![token.tag()];
+        parts.extend(token.attributes().iter().map(|attr| attr.render()));
+        if !token.attr_extra().is_empty() {
+            parts.push(token.attr_extra());
+        }
 
-            let close_tag = format!("<{}>", token.tag());
-            let search_close = format!("<parsex{}>", token.id());
-            html = html.replace(&search_close.as_str(), &close_tag.as_str());
+        // Add slash, if needed
+        if token.is_void() && self.render_style == RenderStyle::Html {
+            return format!("<{}>", parts.join(" "));
+        } else if token.is_self_closing() {
+            parts.push("/".to_string());
         }
 
-        html.to_string()
+        format!("<{}>", parts.join(" "))
     }
 
-    /// Clone stack from starting tag (eg. body, nav menu, footer) to extract certain portion of page.
-    pub fn clone_from(&mut self, token_id: &usize, excludes: &Vec<usize>) -> Option<Stack> {
+    /// Set how void elements are written when rendering modified tokens
+    pub fn set_render_style(&mut self, style: RenderStyle) {
+        self.render_style = style;
+    }
 
-        // Get body contents
-        let code = match self.get_contents(&token_id) {
-            Some(r) => r,
-            None => return None
-        };
+    /// Get how void elements are written when rendering modified tokens
+    pub fn render_style(&self) -> RenderStyle {
+        self.render_style
+    }
 
-        // Start stack
-        let mut res = Stack::new("");
-        res.code = code;
+    /// Clone stack from starting tag (eg. body, nav menu, footer) to extract certain portion of page.  The tag becomes
+    /// the only top level token of the new stack, with ids renumbered from 1 and excluded tokens left out along with their descendants.
+    pub fn clone_from(&self, token_id: &usize, excludes: &Vec<usize>) -> Option<Stack> {
+        if *token_id == 0 || excludes.contains(token_id) || !self.tokens.contains_key(token_id) {
+            return None;
+        }
 
-        // Go through tokens
-        for tag in self.query().parent_id(&token_id).excludes(&excludes).iter() {
-            res.stack.entry(tag.parent_id()).or_default().push(tag.id());
-            if !tag.is_self_closing() {
-                res.depth.entry(tag.tag()).or_default().push(tag.id());
+        // Copy subtree, leaving out excludes within it
+        let mut subtree = self.subtree(*token_id);
+        for exclude_id in excludes {
+            if subtree.tokens.contains_key(exclude_id) {
+                subtree.remove(exclude_id);
             }
-
-            // Add token
-            let token_depth = if let Some(d) = res.depth.get(&tag.tag()) { d.len() } else { 0 };
-            let contents: String = if tag.tag() == "!" { tag.contents() } else { String::new() };
-            self.tokens.insert(tag.id(), tag.clone());
         }
 
+        // Start stack, and renumber tokens into it
+        let mut res = Stack { render_style: self.render_style, ..Default::default() };
+        res.graft_tokens(&subtree, &[*token_id], 0, 0);
         Some(res)
     }
 
@@ -365,16 +669,23 @@ impl Stack {
         for token in self.iter() {
 
             // Comment
-            if token.tag() == "!" {
+            if token.kind() == NodeKind::Comment {
+                let search = format!("<parsex{}>", token.id());
+                html = html.replace(search.as_str(), "");
+                continue;
+            }
+
+            // Text and other nodes
+            if !token.is_element() {
                 let search = format!("<parsex{}>", token.id());
-                html = html.replace(&search.as_str(), "");
+                html = html.replace(search.as_str(), &token.raw_contents());
                 continue;
             }
 
             // Scroll up, if needed
-            if parents.len() > 0 && *parents.last().unwrap() != token.parent_id() {
-                while parents.len() > 0 {
-                    if parents.len() > 0 && *parents.last().unwrap() == token.parent_id() {
+            if !parents.is_empty() && *parents.last().unwrap() != token.parent_id() {
+                while !parents.is_empty() {
+                    if !parents.is_empty() && *parents.last().unwrap() == token.parent_id() {
                         break;
                     } else {
                         parents.pop();
@@ -383,29 +694,24 @@ impl Stack {
                 }
             }
 
-            // Get attribute string
-            let attr_string: String = token.attributes().iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect::<Vec<String>>().join(" ");
-            let single_slash = if !token.is_closed() { "/" } else { "" };
-        let tag_string = format!("{} {} {}", attr_string, token.attr_extra(), single_slash);
-
             // Get indent and newline
             let mut indent = String::new();
-            if parents.len() > 0 && !same_line_tags.contains(&parent_tags.last().unwrap().as_str()) { 
-                indent = repeat(' ').take(parents.len() * 4).collect::<String>();
+            if !parents.is_empty() && !same_line_tags.contains(&parent_tags.last().unwrap().as_str()) { 
+                indent = " ".repeat(parents.len() * 4);
             }
             let suffix = if same_line_tags.contains(&token.tag().as_str()) { "" } else { "\n" };
 
             // Opening tag
-            let open_tag = format!("{}<{} {}>{}", indent, token.tag(), tag_string.trim(), suffix);
+            let open_tag = format!("{}{}{}", indent, self.open_tag(token), suffix);
             html = html.replace(format!("<parsex{}>", token.id()).as_str(), open_tag.as_str());
 
             // Closing tag
             let close_tag = format!("</{}>", token.tag());
             let search_close = format!("</parsex{}>", token.id());
-            html = html.replace(&search_close.as_str(), &close_tag.as_str());
+            html = html.replace(search_close.as_str(), close_tag.as_str());
 
             if token.is_closed() && !same_line_tags.contains(&token.tag().as_str()) {
-                parents.push(token.id().clone());
+                parents.push(token.id());
                 parent_tags.push(token.tag().clone().to_string());
             }
         }
@@ -420,16 +726,186 @@ impl Default for Stack {
     fn default() -> Stack {
         Stack {
             tag_id: 0,
-            parent_id: 0,
             depth: HashMap::new(),
             tokens: HashMap::new(),
             stack: HashMap::new(),
             code: String::new(),
-            position: 0,
-            parent_position: 0,
-            excludes: Vec::new()
+            source: String::new(),
+            cursor: 0,
+            offset: 0,
+            open: Vec::new(),
+            formatting: Vec::new(),
+            walk: Cursor::default(),
+            render_style: RenderStyle::Xhtml,
+            errors: Vec::new(),
+            detached: HashMap::new(),
         }
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{RenderStyle, Stack};
+    use crate::{parse_html, parse_html_with_diagnostics, NodeKind, ParseErrorKind, Span};
+
+    #[test]
+    fn void_elements_are_not_containers() {
+        let html = "<p>a<br>b<IMG SRC=x>c<input disabled>d<wbr/></p>";
+        let stack = parse_html(html);
+        let p = stack.query().tag("p").to_vec()[0].id();
+        for tag in ["br", "img", "input", "wbr"] {
+            let token = stack.query().tag(tag).to_vec()[0];
+            assert!(token.is_void() && token.is_self_closing());
+            assert_eq!(token.parent_id(), p);
+            assert!(stack.child_ids(&token.id()).is_empty());
+        }
+        assert_eq!(stack.get_text(&p), "abcd");
+        assert_eq!(stack.render(), html);
+    }
+
+    #[test]
+    fn render_style_of_modified_void_elements() {
+        let mut stack = parse_html("<p>a<br>b</p>");
+        let br = stack.query().tag("br").ids()[0];
+        stack.get_mut(&br).unwrap().set_attr("class", "x");
+        assert_eq!(stack.render(), "<p>a<br class=\"x\" />b</p>");
+
+        stack.set_render_style(RenderStyle::Html);
+        assert_eq!(stack.render(), "<p>a<br class=\"x\">b</p>");
+    }
+
+    #[test]
+    fn void_end_tags() {
+        let (stack, errors) = parse_html_with_diagnostics("<div>a</br>b</img>c</div>");
+        let div = stack.query().tag("div").to_vec()[0].id();
+        assert_eq!(stack.query().tag("br").to_vec()[0].parent_id(), div);
+        assert!(stack.query().tag("img").to_vec().is_empty());
+        assert_eq!(stack.get_text(&div), "abc");
+        assert_eq!(
+            errors.iter().map(|error| error.kind()).collect::<Vec<_>>(),
+            [ParseErrorKind::UnexpectedEndTag("br".to_string()), ParseErrorKind::UnexpectedEndTag("img".to_string())]
+        );
+    }
+
+    #[test]
+    fn push_closes_void_elements() {
+        let mut stack = Stack::new("<div><br>x</div>");
+        let div = stack.push("div", "", &false, "<div>");
+        let br = stack.push("br", "", &false, "<br>");
+        let text = stack.push_text("x").unwrap();
+        stack.close_tag("div", "</div>");
+
+        assert_eq!(stack.get(&br).unwrap().parent_id(), div);
+        assert_eq!(stack.get(&text).unwrap().parent_id(), div);
+        assert_eq!(stack.render(), "<div><br>x</div>");
+    }
+
+    #[test]
+    fn token_spans() {
+        let html = "<div id=a>\n  <p>one</p>\n  <!-- c --></div>";
+        let stack = parse_html(html);
+        let div = stack.query().tag("div").to_vec()[0];
+        assert_eq!(div.span(), Span::new(0, 10));
+        assert_eq!(&html[div.span().start..div.span().end], div.source());
+        assert_eq!(div.close_span(), Some(Span::new(36, 42)));
+        assert_eq!(div.contents_span(), Some(Span::new(10, 36)));
+
+        let p = stack.query().tag("p").to_vec()[0];
+        assert_eq!(&html[p.span().start..p.close_span().unwrap().end], "<p>one</p>");
+        let text = stack.query().kind(NodeKind::Text).contents("one").to_vec()[0];
+        assert_eq!(text.span(), Span::new(16, 19));
+        let comment = stack.query().kind(NodeKind::Comment).to_vec()[0];
+        assert_eq!(&html[comment.span().start..comment.span().end], "<!-- c -->");
+    }
+
+    #[test]
+    fn implied_and_unclosed_spans() {
+        let stack = parse_html("<p>one<p>two");
+        let p = stack.query().tag("p").to_vec();
+        assert!(p[0].close_span().unwrap().is_empty());
+        assert_eq!(p[0].close_span().unwrap().start, 6);
+        assert_eq!(p[0].contents_span(), Some(Span::new(3, 6)));
+        assert!(p[1].close_span().unwrap().is_empty());
+
+        let stack = parse_html("<b>1<p>2</b>3</p>");
+        let b = stack.query().tag("b").to_vec();
+        assert!(b[1].span().is_empty());
+        assert_eq!(b[1].source(), "");
+    }
+
+    #[test]
+    fn line_col_of_offsets() {
+        let stack = parse_html("ab\n<p>\u{e9}t\u{e9}\r\n<b>x</b>");
+        assert_eq!(stack.line_col(&0), (1, 1));
+        assert_eq!(stack.line_col(&2), (1, 3));
+        assert_eq!(stack.line_col(&3), (2, 1));
+        assert_eq!(stack.line_col(&8), (2, 5));
+        assert_eq!(stack.line_col(&7), (2, 4));
+
+        let b = stack.query().tag("b").to_vec()[0];
+        assert_eq!(stack.line_col(&b.span().start), (3, 1));
+        assert_eq!(stack.line_col(&1000), (3, 9));
+    }
+
+    #[test]
+    fn for_each_match_mut() {
+        let mut stack = parse_html("<ul><li>a</li><li class=x>b</li></ul><p>c</p>");
+        stack.for_each_match_mut("li", |li| {
+            let text = li.text();
+            li.set_attr("data-n", &text);
+            li.set_tag("dd");
+        });
+        assert_eq!(stack.render(), "<ul><dd data-n=\"a\">a</dd><dd class=x data-n=\"b\">b</dd></ul><p>c</p>");
+
+        let mut count = 0;
+        stack.for_each_match_mut("nothing", |_| count += 1);
+        stack.for_each_match_mut("div >", |_| count += 1);
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn for_each_match_mut_skips_removed_matches() {
+        let mut stack = parse_html("<div class=a><div class=a>inner</div></div><div class=a>last</div>");
+        let mut seen = Vec::new();
+        stack.for_each_match_mut(".a", |div| {
+            seen.push(div.id());
+            div.set_contents("<b>new</b>");
+        });
+        assert_eq!(seen.len(), 2);
+        assert_eq!(stack.render(), "<div class=a><b>new</b></div><div class=a><b>new</b></div>");
+    }
+
+    #[test]
+    fn token_mut_updates_the_tree() {
+        let mut stack = parse_html("<p id=a>old <i>x</i></p>");
+        let id = stack.query().id("a").ids()[0];
+        let mut p = stack.get_mut(&id).unwrap();
+        assert_eq!(p.inner_html(), "old <i>x</i>");
+        assert_eq!(p.text(), "old x");
+        assert_eq!(p.set_contents("<b>1</b><b>2</b>").len(), 2);
+        p.set_attr("id", "b");
+        assert_eq!(p.inner_html(), "<b>1</b><b>2</b>");
+        assert_eq!(stack.render(), "<p id=b><b>1</b><b>2</b></p>");
+        assert!(stack.get_mut(&999).is_none());
+    }
+
+    #[test]
+    fn pull_mut_and_get_many_mut() {
+        let mut stack = parse_html("<p>a</p><p>b</p>");
+        while let Some(mut token) = stack.pull_mut() {
+            if token.is_element() {
+                token.set_attr("class", "seen");
+            }
+        }
+        assert_eq!(stack.render(), "<p class=\"seen\">a</p><p class=\"seen\">b</p>");
+
+        let ids = stack.query().tag("p").ids();
+        let tokens = stack.get_many_mut(&[ids[1], 999, ids[0], ids[1]]);
+        assert_eq!(tokens.iter().map(|token| token.id()).collect::<Vec<_>>(), [ids[1], ids[0]]);
+        for token in tokens {
+            token.del_attr("class");
+        }
+        assert_eq!(stack.render(), "<p>a</p><p>b</p>");
+    }
+}
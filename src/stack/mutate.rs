@@ -0,0 +1,441 @@
+use super::{Position, Stack, PLACEHOLDER};
+use crate::elements::{is_raw_text, is_rcdata};
+use crate::parse_fragment;
+use crate::span::Span;
+use crate::token::{NodeKind, Token};
+use regex::Captures;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Structural changes to the tree.  Tokens, the parent / children map, depths and the placeholder code
+/// are all updated together, so rendering and later queries see the change.
+impl Stack {
+    /// Add token as last child of parent, or at the end of the document if parent id is 0.  Returns id of the new token.
+    pub fn append_child(&mut self, parent_id: &usize, token: Token) -> Option<usize> {
+        let index = self.child_ids(parent_id).len();
+        self.insert_token(*parent_id, index, token)
+    }
+
+    /// Add token as first child of parent, or at the start of the document if parent id is 0.  Returns id of the new token.
+    pub fn prepend_child(&mut self, parent_id: &usize, token: Token) -> Option<usize> {
+        self.insert_token(*parent_id, 0, token)
+    }
+
+    /// Add token directly before sibling.  Returns id of the new token.
+    pub fn insert_before(&mut self, sibling_id: &usize, token: Token) -> Option<usize> {
+        let (parent_id, index) = self.location(sibling_id)?;
+        self.insert_token(parent_id, index, token)
+    }
+
+    /// Add token directly after sibling.  Returns id of the new token.
+    pub fn insert_after(&mut self, sibling_id: &usize, token: Token) -> Option<usize> {
+        let (parent_id, index) = self.location(sibling_id)?;
+        self.insert_token(parent_id, index + 1, token)
+    }
+
+    /// Remove token along with all of its descendants, returns the removed token
+    pub fn remove(&mut self, token_id: &usize) -> Option<Token> {
+        if !self.tokens.contains_key(token_id) {
+            return None;
+        }
+        self.take(*token_id);
+        self.remove_descendants(token_id);
+        self.tokens.remove(token_id)
+    }
+
+    /// Replace token and all of its descendants with another token.  Returns id of the new token.
+    pub fn replace_with(&mut self, token_id: &usize, token: Token) -> Option<usize> {
+        let (parent_id, index) = self.location(token_id)?;
+        let new_id = self.insert_token(parent_id, index, token)?;
+        self.remove(token_id);
+        Some(new_id)
+    }
+
+    /// Take token and its descendants out of the tree, keeping them so they can be placed again with `move_to()`
+    pub fn detach(&mut self, token_id: &usize) -> bool {
+        if self.location(token_id).is_none() {
+            return false;
+        }
+        let code = self.take(*token_id);
+        self.detached.insert(*token_id, code);
+        self.tokens.get_mut(token_id).unwrap().set_parent_id(&0);
+        true
+    }
+
+    /// Move token and its descendants to be the child of parent at index, or the last child if index is past the end.
+    /// Works for both attached and detached tokens.  A token cannot be moved within itself.
+    pub fn move_to(&mut self, token_id: &usize, parent_id: &usize, index: &usize) -> bool {
+        if (!self.detached.contains_key(token_id) && self.location(token_id).is_none())
+            || !self.can_contain(parent_id)
+            || *parent_id == *token_id
+            || self.ancestors(parent_id).iter().any(|ancestor| ancestor.id() == *token_id)
+        {
+            return false;
+        }
+
+        let code = self.take(*token_id);
+        self.attach(*token_id, *parent_id, *index, &code);
+        true
+    }
+
+    /// Parse HTML fragment and insert it relative to target, as per `insertAdjacentHTML`.  The fragment is parsed in the
+    /// context of the element it is inserted within.  Returns ids of the new top level tokens, which are queryable straight away.
+    pub fn insert_html(&mut self, target_id: &usize, position: Position, html: &str) -> Vec<usize> {
+        let (parent_id, index) = match self.insertion_point(target_id, position) {
+            Some(r) => r,
+            None => return Vec::new(),
+        };
+
+        // Parse fragment within context, and take the children of the context element (the first token) in place of it
+        let context = self.tokens.get(&parent_id).map(|parent| parent.tag()).unwrap_or_default();
+        let fragment = parse_fragment(html, &context);
+        let context_id = if context.is_empty() { 0 } else { 1 };
+        let mut top_ids = Vec::new();
+        for token_id in fragment.child_ids(&0) {
+            if *token_id == context_id {
+                top_ids.extend(fragment.child_ids(token_id));
+            } else {
+                top_ids.push(*token_id);
+            }
+        }
+
+        self.graft_tokens(&fragment, &top_ids, parent_id, index)
+    }
+
+    /// Wrap token within the element given as HTML (eg. `<div class="table-scroll">`), parsed in the context of the token's
+    /// parent.  The token goes within the innermost first element if the HTML is nested.  Returns id of the outer wrapper.
+    pub fn wrap(&mut self, token_id: &usize, html: &str) -> Option<usize> {
+        self.location(token_id)?;
+        let inserted_ids = self.insert_html(token_id, Position::BeforeBegin, html);
+        let wrapper_id = match inserted_ids.iter().copied().find(|id| self.tokens.get(id).unwrap().is_element()) {
+            Some(r) => r,
+            None => {
+                for id in inserted_ids {
+                    self.remove(&id);
+                }
+                return None;
+            }
+        };
+
+        // Descend to the innermost first element, giving each a closing tag if the HTML left them open
+        let mut inner_id = wrapper_id;
+        loop {
+            let inner = self.tokens.get_mut(&inner_id).unwrap();
+            if inner.close_source().is_empty() && !inner.is_void() {
+                let close_source = format!("</{}>", inner.tag());
+                inner.set_close_source(&close_source);
+            }
+            match self.child_ids(&inner_id).iter().copied().find(|id| self.tokens.get(id).unwrap().is_element()) {
+                Some(child_id) => inner_id = child_id,
+                None => break,
+            }
+        }
+
+        // Place token as last child of the innermost element
+        let index = self.child_ids(&inner_id).len();
+        if !self.move_to(token_id, &inner_id, &index) {
+            for id in inserted_ids {
+                self.remove(&id);
+            }
+            return None;
+        }
+        Some(wrapper_id)
+    }
+
+    /// Remove element while keeping its children, which take its place within the parent
+    pub fn unwrap(&mut self, token_id: &usize) -> bool {
+        let (parent_id, index) = match self.location(token_id) {
+            Some(r) => r,
+            None => return false,
+        };
+        if !self.can_contain(token_id) {
+            return false;
+        }
+
+        for (offset, child_id) in self.child_ids(token_id).to_vec().iter().enumerate() {
+            self.move_to(child_id, &parent_id, &(index + offset));
+        }
+        self.remove(token_id);
+        true
+    }
+
+    /// Copy token and its descendants with fresh ids, placing the copy directly after the token.  Returns id of the copy.
+    pub fn clone_subtree(&mut self, token_id: &usize) -> Option<usize> {
+        let (parent_id, index) = self.location(token_id)?;
+        let subtree = self.subtree(*token_id);
+        self.graft_tokens(&subtree, &[*token_id], parent_id, index + 1).first().copied()
+    }
+
+    /// Set contents of token as HTML, parsed in the context of the element and replacing all of its children, or the whole
+    /// document if token id is 0.  Raw text and RCDATA elements, and non-element nodes, take the contents as is.
+    /// Returns ids of the new top level tokens.
+    pub fn set_inner_html(&mut self, token_id: &usize, html: &str) -> Vec<usize> {
+        if *token_id == 0 {
+            self.remove_descendants(token_id);
+            self.code.clear();
+            return self.insert_html(token_id, Position::BeforeEnd, html);
+        }
+        let (tag, kind, is_void) = match self.tokens.get(token_id) {
+            Some(r) => (r.tag(), r.kind(), r.is_void()),
+            None => return Vec::new(),
+        };
+
+        // Nodes and raw text elements hold their contents directly
+        if kind != NodeKind::Element {
+            self.tokens.get_mut(token_id).unwrap().replace_contents(html);
+            return Vec::new();
+        } else if is_void {
+            return Vec::new();
+        } else if is_raw_text(&tag) || is_rcdata(&tag) {
+            self.tokens.get_mut(token_id).unwrap().replace_contents(html);
+            return Vec::new();
+        }
+
+        self.remove_descendants(token_id);
+        self.set_code_contents(token_id, "");
+        self.insert_html(token_id, Position::BeforeEnd, html)
+    }
+
+    /// Replace code between token's placeholders, within the document or the detached tree holding it
+    pub(super) fn set_code_contents(&mut self, token_id: &usize, contents: &str) {
+        let open = format!("<parsex{}>", token_id);
+        let close = format!("</parsex{}>", token_id);
+        let code = self.code_mut(*token_id);
+        if let Some(range) = code_range(code, *token_id) {
+            if code[range.clone()].ends_with(&close) {
+                code.replace_range(range.start + open.len()..range.end - close.len(), contents);
+            }
+        }
+    }
+
+    /// Copy all top level tokens of another stack, along with their descendants, relative to target with fresh ids.
+    /// The reverse of `clone_from()`.  Returns ids of the new top level tokens.
+    pub fn graft(&mut self, target_id: &usize, position: Position, other: &Stack) -> Vec<usize> {
+        match self.insertion_point(target_id, position) {
+            Some((parent_id, index)) => self.graft_tokens(other, other.child_ids(&0), parent_id, index),
+            None => Vec::new(),
+        }
+    }
+
+    /// Get parent id and index to insert at relative to target, None if the parent cannot hold children
+    fn insertion_point(&self, target_id: &usize, position: Position) -> Option<(usize, usize)> {
+        let (parent_id, index) = match position {
+            Position::AfterBegin => (*target_id, 0),
+            Position::BeforeEnd => (*target_id, self.child_ids(target_id).len()),
+            Position::BeforeBegin => self.location(target_id)?,
+            Position::AfterEnd => self.location(target_id).map(|(parent_id, index)| (parent_id, index + 1))?,
+        };
+        if !self.can_contain(&parent_id) {
+            return None;
+        }
+        Some((parent_id, index))
+    }
+
+    /// Copy tokens and their descendants from another stack as children of parent starting at index, with fresh ids.
+    /// Returns the new ids of the tokens.
+    pub(super) fn graft_tokens(&mut self, other: &Stack, token_ids: &[usize], parent_id: usize, index: usize) -> Vec<usize> {
+        let mut res = Vec::new();
+        for token_id in token_ids {
+            let Some(range) = code_range(&other.code, *token_id) else { continue };
+            let mut ids: HashMap<usize, usize> = HashMap::new();
+            let new_id = self.copy_subtree(other, *token_id, parent_id, &mut ids);
+
+            // Renumber placeholders within code of the subtree
+            let code = PLACEHOLDER.replace_all(&other.code[range], |cap: &Captures| {
+                let old_id = cap[2].parse::<usize>().unwrap();
+                format!("<{}parsex{}>", &cap[1], ids.get(&old_id).unwrap_or(&old_id))
+            });
+
+            self.attach(new_id, parent_id, index + res.len(), &code);
+            res.push(new_id);
+        }
+        res
+    }
+
+    /// Copy token and its descendants from another stack with fresh ids, without placing it within the tree
+    fn copy_subtree(&mut self, other: &Stack, token_id: usize, parent_id: usize, ids: &mut HashMap<usize, usize>) -> usize {
+        self.tag_id += 1;
+        let new_id = self.tag_id;
+        ids.insert(token_id, new_id);
+
+        // Spans refer to the other source, so are reset
+        let mut token = other.tokens.get(&token_id).unwrap().clone();
+        token.set_id(&new_id);
+        token.set_parent_id(&parent_id);
+        token.set_span(&Span::default());
+        if token.close_span().is_some() {
+            token.set_close_span(&Span::default());
+        }
+        self.tokens.insert(new_id, token);
+
+        let children: Vec<usize> =
+            other.child_ids(&token_id).iter().map(|child_id| self.copy_subtree(other, *child_id, new_id, ids)).collect();
+        if !children.is_empty() {
+            self.stack.insert(new_id, children);
+        }
+        new_id
+    }
+
+    /// Copy token and its descendants into a new stack with the same ids, where the token is the only top level token
+    pub(super) fn subtree(&self, token_id: usize) -> Stack {
+        let code = self.code_of(token_id);
+        let mut res = Stack {
+            code: code.get(code_range(code, token_id).unwrap()).unwrap().to_string(),
+            ..Default::default()
+        };
+        res.stack.insert(0, vec![token_id]);
+
+        let mut ids = vec![token_id];
+        while let Some(id) = ids.pop() {
+            res.tokens.insert(id, self.tokens.get(&id).unwrap().clone());
+            let children = self.child_ids(&id);
+            if !children.is_empty() {
+                res.stack.insert(id, children.to_vec());
+            }
+            ids.extend(children);
+        }
+        res
+    }
+
+    /// Insert new token as child of parent at index, returns its id
+    fn insert_token(&mut self, parent_id: usize, index: usize, mut token: Token) -> Option<usize> {
+        if !self.can_contain(&parent_id) {
+            return None;
+        }
+        self.tag_id += 1;
+        token.set_id(&self.tag_id);
+
+        // Get placeholder code, elements with contents are closed with the body of raw text and RCDATA elements kept
+        // on the token.  Contents of other elements are parsed into children once placed.
+        let mut code = format!("<parsex{}>", self.tag_id);
+        let mut html = String::new();
+        if token.kind() == NodeKind::Element && !token.is_void() && !token.is_self_closing() {
+            if !is_raw_text(&token.tag()) && !is_rcdata(&token.tag()) {
+                html = token.raw_contents();
+                token.replace_contents("");
+            }
+            code.push_str(&format!("</parsex{}>", self.tag_id));
+            token.mark_closed();
+        }
+
+        let token_id = self.tag_id;
+        self.tokens.insert(token_id, token);
+        self.attach(token_id, parent_id, index, &code);
+        if !html.is_empty() {
+            self.insert_html(&token_id, Position::BeforeEnd, &html);
+        }
+        Some(token_id)
+    }
+
+    /// Place token and its code as child of parent at index, and update depths of it and its descendants
+    fn attach(&mut self, token_id: usize, parent_id: usize, index: usize, code: &str) {
+        // Parent without a closing placeholder (eg. self-closing `<svg/>`) must be closed to hold children
+        if parent_id > 0 && !self.tokens.get(&parent_id).unwrap().is_closed() {
+            let open = format!("<parsex{}>", parent_id);
+            let parent_code = self.code_mut(parent_id);
+            *parent_code = parent_code.replacen(&open, &format!("{}</parsex{}>", open, parent_id), 1);
+            let parent = self.tokens.get_mut(&parent_id).unwrap();
+            parent.mark_closed();
+            parent.mark_not_self_closing();
+        }
+
+        // Insert code before the sibling at index, or at the end of the parent
+        let children = self.stack.entry(parent_id).or_default();
+        let index = index.min(children.len());
+        let sibling_id = children.get(index).copied();
+        children.insert(index, token_id);
+        let parent_code = self.code_mut(parent_id);
+        let pos = match sibling_id {
+            Some(sibling_id) => parent_code.find(&format!("<parsex{}>", sibling_id)).unwrap(),
+            None if parent_id == 0 => parent_code.len(),
+            None => parent_code.find(&format!("</parsex{}>", parent_id)).unwrap(),
+        };
+        parent_code.insert_str(pos, code);
+
+        self.tokens.get_mut(&token_id).unwrap().set_parent_id(&parent_id);
+        self.update_depth(token_id);
+    }
+
+    /// Remove token from its parent or the detached tokens, returns its code
+    fn take(&mut self, token_id: usize) -> String {
+        if let Some(code) = self.detached.remove(&token_id) {
+            return code;
+        }
+
+        let parent_id = self.tokens.get(&token_id).unwrap().parent_id();
+        if let Some(children) = self.stack.get_mut(&parent_id) {
+            children.retain(|id| *id != token_id);
+        }
+        let code = self.code_mut(token_id);
+        match code_range(code, token_id) {
+            Some(range) => code.drain(range).collect(),
+            None => String::new(),
+        }
+    }
+
+    /// Get code holding token's placeholders, that of the document or of the detached tree the token is within
+    fn code_of(&self, token_id: usize) -> &str {
+        let open = format!("<parsex{}>", token_id);
+        if token_id == 0 || self.code.contains(&open) {
+            return &self.code;
+        }
+        self.detached.values().find(|code| code.contains(&open)).unwrap_or(&self.code)
+    }
+
+    /// Get mutable code holding token's placeholders, that of the document or of the detached tree the token is within
+    fn code_mut(&mut self, token_id: usize) -> &mut String {
+        let open = format!("<parsex{}>", token_id);
+        if token_id == 0 || self.code.contains(&open) {
+            return &mut self.code;
+        }
+        self.detached.values_mut().find(|code| code.contains(&open)).unwrap_or(&mut self.code)
+    }
+
+    /// Get parent id and index within parent of an attached token
+    fn location(&self, token_id: &usize) -> Option<(usize, usize)> {
+        let parent_id = self.tokens.get(token_id)?.parent_id();
+        let index = self.child_ids(&parent_id).iter().position(|id| id == token_id)?;
+        Some((parent_id, index))
+    }
+
+    /// Check if token may hold children, the document always can
+    fn can_contain(&self, parent_id: &usize) -> bool {
+        if *parent_id == 0 {
+            return true;
+        }
+        match self.tokens.get(parent_id) {
+            Some(parent) => {
+                parent.is_element()
+                    && !parent.is_void()
+                    && !is_raw_text(&parent.tag())
+                    && !is_rcdata(&parent.tag())
+            }
+            None => false,
+        }
+    }
+
+    /// Set depth of token and its descendants, the number of elements with the same tag it is nested within, plus itself
+    fn update_depth(&mut self, token_id: usize) {
+        let token = self.tokens.get(&token_id).unwrap();
+        if token.is_element() {
+            let tag = token.tag();
+            let depth = 1 + self.ancestors(&token_id).iter().filter(|ancestor| ancestor.tag() == tag).count();
+            self.tokens.get_mut(&token_id).unwrap().set_depth(&depth);
+        }
+        for child_id in self.child_ids(&token_id).to_vec() {
+            self.update_depth(child_id);
+        }
+    }
+}
+
+/// Get byte range of token's placeholders and everything between them within code
+fn code_range(code: &str, token_id: usize) -> Option<Range<usize>> {
+    let open = format!("<parsex{}>", token_id);
+    let start = code.find(&open)?;
+    let close = format!("</parsex{}>", token_id);
+    let end = match code[start..].find(&close) {
+        Some(r) => start + r + close.len(),
+        None => start + open.len(),
+    };
+    Some(start..end)
+}
@@ -0,0 +1,186 @@
+use super::Stack;
+use crate::selector::Selector;
+use crate::token::Token;
+
+/// Tree navigation keyed by token id.  These read the parent / children map directly, so unlike
+/// queries they do not move the position used by `pull()` and `iter()`.  Siblings and children
+/// include text, comment and other non-element nodes.
+impl Stack {
+    /// Get parent of token, None for top level tokens
+    pub fn parent(&self, token_id: &usize) -> Option<&Token> {
+        let parent_id = self.tokens.get(token_id)?.parent_id();
+        self.tokens.get(&parent_id)
+    }
+
+    /// Get ancestors of token, nearest first
+    pub fn ancestors(&self, token_id: &usize) -> Vec<&Token> {
+        let mut res = Vec::new();
+        let mut current = self.parent(token_id);
+        while let Some(token) = current {
+            res.push(token);
+            current = self.parent(&token.id());
+        }
+        res
+    }
+
+    /// Get nearest element matching CSS selector, starting with the token itself.  An invalid selector matches nothing.
+    pub fn closest(&self, token_id: &usize, selector: &str) -> Option<&Token> {
+        let selector = Selector::parse(selector).ok()?;
+        self.closest_with(token_id, &selector)
+    }
+
+    /// Get nearest element matching pre-compiled CSS selector, starting with the token itself
+    pub fn closest_with(&self, token_id: &usize, selector: &Selector) -> Option<&Token> {
+        let token = self.tokens.get(token_id)?;
+        if selector.matches(self, token_id) {
+            return Some(token);
+        }
+        self.ancestors(token_id).into_iter().find(|ancestor| selector.matches(self, &ancestor.id()))
+    }
+
+    /// Get next sibling of token
+    pub fn next_sibling(&self, token_id: &usize) -> Option<&Token> {
+        let index = self.index_in_parent(token_id)?;
+        let sibling_id = self.sibling_ids(token_id).get(index + 1).copied()?;
+        self.tokens.get(&sibling_id)
+    }
+
+    /// Get previous sibling of token
+    pub fn prev_sibling(&self, token_id: &usize) -> Option<&Token> {
+        let index = self.index_in_parent(token_id)?.checked_sub(1)?;
+        let sibling_id = self.sibling_ids(token_id).get(index).copied()?;
+        self.tokens.get(&sibling_id)
+    }
+
+    /// Get all other children of the token's parent, in document order
+    pub fn siblings(&self, token_id: &usize) -> Vec<&Token> {
+        self.sibling_ids(token_id)
+            .iter()
+            .filter(|id| *id != token_id)
+            .filter_map(|id| self.tokens.get(id))
+            .collect()
+    }
+
+    /// Get first child of token
+    pub fn first_child(&self, token_id: &usize) -> Option<&Token> {
+        let child_id = self.stack.get(token_id)?.first()?;
+        self.tokens.get(child_id)
+    }
+
+    /// Get last child of token
+    pub fn last_child(&self, token_id: &usize) -> Option<&Token> {
+        let child_id = self.stack.get(token_id)?.last()?;
+        self.tokens.get(child_id)
+    }
+
+    /// Get all descendants of token in document order, or of the whole document if token id is 0
+    pub fn descendants(&self, token_id: &usize) -> Vec<&Token> {
+        let mut res = Vec::new();
+        for child_id in self.child_ids(token_id) {
+            if let Some(child) = self.tokens.get(child_id) {
+                res.push(child);
+                res.extend(self.descendants(child_id));
+            }
+        }
+        res
+    }
+
+    /// Get index of token amongst its parent's children, starting at 0
+    pub fn index_in_parent(&self, token_id: &usize) -> Option<usize> {
+        self.sibling_ids(token_id).iter().position(|id| id == token_id)
+    }
+
+    /// Get ids of children of token's parent, including the token itself.  Empty if token is detached.
+    fn sibling_ids(&self, token_id: &usize) -> &[usize] {
+        match self.tokens.get(token_id) {
+            Some(token) if self.child_ids(&token.parent_id()).contains(token_id) => self.child_ids(&token.parent_id()),
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_html, Selector, Token};
+
+    /// Get tags of tokens, or their contents for other nodes
+    fn names(tokens: Vec<&Token>) -> Vec<String> {
+        tokens.iter().map(|token| if token.is_element() { token.tag() } else { token.contents() }).collect()
+    }
+
+    #[test]
+    fn parent_and_ancestors() {
+        let stack = parse_html("<div class=a><section><p>x<b>y</b></p></section></div>");
+        let b = stack.query().tag("b").ids()[0];
+        assert_eq!(stack.parent(&b).unwrap().tag(), "p");
+        assert_eq!(names(stack.ancestors(&b)), ["p", "section", "div"]);
+
+        let div = stack.query().tag("div").ids()[0];
+        assert!(stack.parent(&div).is_none());
+        assert!(stack.ancestors(&div).is_empty());
+        assert!(stack.parent(&999).is_none());
+    }
+
+    #[test]
+    fn closest() {
+        let stack = parse_html("<div class=a><section class=a><p>x<b>y</b></p></section></div>");
+        let b = stack.query().tag("b").ids()[0];
+        assert_eq!(stack.closest(&b, "b").unwrap().id(), b);
+        assert_eq!(stack.closest(&b, ".a").unwrap().tag(), "section");
+        assert_eq!(stack.closest(&b, "div.a").unwrap().tag(), "div");
+        assert!(stack.closest(&b, "ul").is_none());
+        assert!(stack.closest(&b, "div >").is_none());
+
+        let selector = Selector::parse("section > p").unwrap();
+        assert_eq!(stack.closest_with(&b, &selector).unwrap().tag(), "p");
+    }
+
+    #[test]
+    fn siblings_include_text_nodes() {
+        let stack = parse_html("<ul>one<li>a</li><!--c--><li>b</li></ul>");
+        let li = stack.query().tag("li").ids();
+        assert_eq!(names(stack.siblings(&li[0])), ["one", "<!--c-->", "li"]);
+        assert_eq!(stack.next_sibling(&li[0]).unwrap().contents(), "<!--c-->");
+        assert_eq!(stack.prev_sibling(&li[0]).unwrap().contents(), "one");
+        assert!(stack.next_sibling(&li[1]).is_none());
+        assert_eq!(stack.index_in_parent(&li[1]), Some(3));
+
+        let ul = stack.query().tag("ul").ids()[0];
+        assert_eq!(stack.first_child(&ul).unwrap().contents(), "one");
+        assert_eq!(stack.last_child(&ul).unwrap().id(), li[1]);
+        assert!(stack.first_child(&li[0]).unwrap().is_text());
+        assert!(stack.siblings(&ul).is_empty());
+    }
+
+    #[test]
+    fn descendants_in_document_order() {
+        let stack = parse_html("<div><p>a<b>b</b></p><i>c</i></div><span>d</span>");
+        let div = stack.query().tag("div").ids()[0];
+        assert_eq!(names(stack.descendants(&div)), ["p", "a", "b", "b", "i", "c"]);
+        assert_eq!(stack.descendants(&0).len(), 9);
+
+        let b = stack.query().tag("b").ids()[0];
+        assert_eq!(names(stack.descendants(&b)), ["b"]);
+        assert!(stack.descendants(&999).is_empty());
+    }
+
+    #[test]
+    fn navigation_does_not_move_pull_position() {
+        let mut stack = parse_html("<p>a</p><p>b</p>");
+        let first = stack.pull().unwrap().id();
+        stack.next_sibling(&first);
+        stack.descendants(&0);
+        assert_eq!(stack.pull().unwrap().parent_id(), first);
+    }
+
+    #[test]
+    fn detached_tokens_have_no_siblings() {
+        let mut stack = parse_html("<p>a</p><p>b</p>");
+        let p = stack.query().tag("p").ids()[0];
+        assert!(stack.detach(&p));
+        assert!(stack.siblings(&p).is_empty());
+        assert!(stack.next_sibling(&p).is_none());
+        assert_eq!(stack.index_in_parent(&p), None);
+        assert_eq!(names(stack.descendants(&p)), ["a"]);
+    }
+}
@@ -0,0 +1,590 @@
+use super::Stack;
+use crate::attribute::Attribute;
+use crate::elements::{
+    is_formatting, is_heading, is_special, is_void, CLOSES_P, HEADINGS, MARKERS, SCOPE_BOUNDARIES,
+};
+use crate::error::ParseErrorKind;
+use crate::span::Span;
+use crate::token::Token;
+
+/// Scope used when checking if an element is open, as per the HTML tree construction rules
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scope {
+    Default,
+    ListItem,
+    Button,
+    Table,
+}
+
+impl Scope {
+    /// Check if tag bounds this scope
+    fn is_boundary(&self, tag: &str) -> bool {
+        match self {
+            Scope::Default => SCOPE_BOUNDARIES.contains(&tag),
+            Scope::ListItem => SCOPE_BOUNDARIES.contains(&tag) || tag == "ol" || tag == "ul",
+            Scope::Button => SCOPE_BOUNDARIES.contains(&tag) || tag == "button",
+            Scope::Table => tag == "html" || tag == "table" || tag == "template",
+        }
+    }
+}
+
+impl Stack {
+    /// Process start tag, closing any elements with implied end tags before inserting it
+    pub(super) fn start_tag(
+        &mut self,
+        tag: &str,
+        attributes: Vec<Attribute>,
+        duplicates: Vec<String>,
+        is_single: &bool,
+        tag_string: &str,
+    ) -> usize {
+        // Close open p element, if needed
+        if CLOSES_P.contains(&tag) && self.in_scope(&["p"], Scope::Button).is_some() {
+            self.close_implied(&["p"]);
+        }
+
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" if is_heading(&self.tag_of(self.current_node())) => {
+                self.pop("");
+            }
+            "li" => self.close_list_item(&["li"]),
+            "dd" | "dt" => self.close_list_item(&["dd", "dt"]),
+            "option" | "optgroup" if self.tag_of(self.current_node()) == "option" => {
+                self.pop("");
+            }
+            "button" if self.in_scope(&["button"], Scope::Default).is_some() => {
+                self.close_implied(&["button"]);
+            }
+            "tr" => {
+                self.close_cell();
+                self.close_implied_in(&["tr"], Scope::Table);
+            }
+            "td" | "th" => self.close_cell(),
+            "thead" | "tbody" | "tfoot" => {
+                self.close_cell();
+                self.close_implied_in(&["tr"], Scope::Table);
+                self.close_implied_in(&["thead", "tbody", "tfoot"], Scope::Table);
+            }
+            "a" => {
+                // An open 'a' element is closed by another
+                if let Some(index) = self.formatting_index("a") {
+                    let tag_id = self.formatting[index].unwrap();
+                    self.adoption_agency("a", "");
+                    self.formatting.retain(|entry| *entry != Some(tag_id));
+                    if let Some(pos) = self.open.iter().position(|id| *id == tag_id) {
+                        self.close_at(pos, "");
+                    }
+                }
+            }
+            "nobr" => {
+                self.reconstruct_formatting();
+                if self.in_scope(&["nobr"], Scope::Default).is_some() {
+                    self.adoption_agency("nobr", "");
+                }
+            }
+            _ => {}
+        }
+
+        // Re-open misnested formatting elements, if needed
+        let reconstructs = !is_special(tag)
+            || matches!(
+                tag,
+                "applet" | "area" | "br" | "button" | "embed" | "img" | "input" | "keygen"
+                    | "marquee" | "object" | "select" | "wbr" | "xmp"
+            );
+        if reconstructs {
+            self.reconstruct_formatting();
+        }
+
+        // Trailing solidus only self-closes void and foreign elements, others are opened as normal
+        let is_ignored = *is_single && !is_void(tag) && !self.in_foreign_content(tag);
+        let is_single = &(*is_single && !is_ignored);
+
+        // Insert element
+        let tag_id = self.insert(tag, attributes, duplicates, is_single, tag_string);
+        if is_ignored {
+            let span = self.tokens.get(&tag_id).unwrap().span();
+            self.error(ParseErrorKind::NonVoidSelfClosingTag(tag.to_string()), &span);
+        }
+        if *is_single {
+            return tag_id;
+        } else if is_formatting(tag) {
+            self.push_formatting(tag_id);
+        } else if MARKERS.contains(&tag) {
+            self.formatting.push(None);
+        }
+
+        tag_id
+    }
+
+    /// Check if tag is an SVG or MathML element, or is within one
+    fn in_foreign_content(&self, tag: &str) -> bool {
+        tag == "svg" || tag == "math" || self.open.iter().any(|id| matches!(self.tag_of(*id).as_str(), "svg" | "math"))
+    }
+
+    /// Process end tag, closing misnested and implied elements as needed
+    pub(super) fn end_tag(&mut self, tag: &str, tag_string: &str) {
+        // Formatting elements
+        if is_formatting(tag) {
+            if !self.adoption_agency(tag, tag_string) {
+                self.any_other_end_tag(tag, tag_string);
+            }
+            return;
+        }
+
+        let scope = match tag {
+            "p" => Scope::Button,
+            "li" => Scope::ListItem,
+            "td" | "th" | "tr" | "thead" | "tbody" | "tfoot" | "table" | "caption" => Scope::Table,
+            _ if is_special(tag) => Scope::Default,
+            _ => {
+                self.any_other_end_tag(tag, tag_string);
+                return;
+            }
+        };
+
+        // Stray end tag of p, insert an empty p element to close
+        if tag == "p" && self.in_scope(&["p"], scope).is_none() {
+            self.error(ParseErrorKind::UnexpectedEndTag(tag.to_string()), &self.end_tag_span(tag_string));
+            self.insert("p", Vec::new(), Vec::new(), &false, "");
+        }
+
+        // Stray end tag of br, treat as br element
+        if tag == "br" {
+            self.error(ParseErrorKind::UnexpectedEndTag(tag.to_string()), &self.end_tag_span(tag_string));
+            self.insert("br", Vec::new(), Vec::new(), &true, tag_string);
+            return;
+        }
+
+        // Close element, or ignore if not open
+        let tags = if is_heading(tag) { HEADINGS.to_vec() } else { vec![tag] };
+        match self.in_scope(&tags, scope) {
+            Some(pos) => self.close_at(pos, tag_string),
+            None => self.ignore_end_tag(tag, tag_string),
+        }
+    }
+
+    /// Ignore stray end tag, leaving it within the code as is
+    fn ignore_end_tag(&mut self, tag: &str, tag_string: &str) {
+        if !tag_string.is_empty() {
+            self.error(ParseErrorKind::UnexpectedEndTag(tag.to_string()), &self.end_tag_span(tag_string));
+        }
+        self.skip(tag_string);
+    }
+
+    /// Get span of end tag at the cursor
+    fn end_tag_span(&self, tag_string: &str) -> Span {
+        Span::new(self.offset, self.offset + tag_string.len())
+    }
+
+    /// End of file, any elements written within the source that are still open are missing their end tags
+    pub(crate) fn end_of_file(&mut self) {
+        // Close elements at the very end of code, so each holds all of its contents between its placeholders
+        self.cursor = self.code.len();
+        self.offset = self.source.len();
+        while !self.open.is_empty() {
+            self.pop("");
+        }
+    }
+
+    /// Any other end tag, close the nearest open element with same name unless a special element is in the way
+    fn any_other_end_tag(&mut self, tag: &str, tag_string: &str) {
+        for pos in (0..self.open.len()).rev() {
+            let node_tag = self.tag_of(self.open[pos]);
+            if node_tag == tag {
+                self.close_at(pos, tag_string);
+                return;
+            } else if is_special(&node_tag) {
+                break;
+            }
+        }
+        self.ignore_end_tag(tag, tag_string);
+    }
+
+    /// Get position within open elements of the nearest element with one of the tags, if it is in scope
+    fn in_scope(&self, tags: &[&str], scope: Scope) -> Option<usize> {
+        for pos in (0..self.open.len()).rev() {
+            let node_tag = self.tag_of(self.open[pos]);
+            if tags.contains(&node_tag.as_str()) {
+                return Some(pos);
+            } else if scope.is_boundary(&node_tag) {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Close all open elements from the given position onwards, with tag string closing the element at the position
+    fn close_at(&mut self, pos: usize, tag_string: &str) {
+        while self.open.len() > pos {
+            let string = if self.open.len() == pos + 1 { tag_string } else { "" };
+            let tag_id = self.pop(string);
+            if MARKERS.contains(&self.tag_of(tag_id).as_str()) {
+                self.clear_formatting_to_marker();
+            }
+        }
+    }
+
+    /// Generate implied end tags and close the nearest element with one of the tags
+    fn close_implied(&mut self, tags: &[&str]) {
+        self.close_implied_in(tags, Scope::Default);
+    }
+
+    /// Close the nearest element with one of the tags if it is in the given scope
+    fn close_implied_in(&mut self, tags: &[&str], scope: Scope) {
+        if let Some(pos) = self.in_scope(tags, scope) {
+            self.close_at(pos, "");
+        }
+    }
+
+    /// Close open list item (li, dd, dt) when a new one starts
+    fn close_list_item(&mut self, tags: &[&str]) {
+        for pos in (0..self.open.len()).rev() {
+            let node_tag = self.tag_of(self.open[pos]);
+            if tags.contains(&node_tag.as_str()) {
+                self.close_at(pos, "");
+                return;
+            } else if is_special(&node_tag) && !matches!(node_tag.as_str(), "address" | "div" | "p") {
+                return;
+            }
+        }
+    }
+
+    /// Close open table cell when a new cell or row starts
+    fn close_cell(&mut self) {
+        if let Some(pos) = self.in_scope(&["td", "th"], Scope::Table) {
+            self.close_at(pos, "");
+        }
+    }
+
+    /// Get tag name of token
+    fn tag_of(&self, tag_id: usize) -> String {
+        match self.tokens.get(&tag_id) {
+            Some(token) => token.tag(),
+            None => String::new(),
+        }
+    }
+
+    /// Get index of formatting element with tag after the last marker
+    fn formatting_index(&self, tag: &str) -> Option<usize> {
+        for index in (0..self.formatting.len()).rev() {
+            match self.formatting[index] {
+                None => return None,
+                Some(tag_id) if self.tag_of(tag_id) == tag => return Some(index),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Push onto list of active formatting elements, keeping no more than three identical elements
+    fn push_formatting(&mut self, tag_id: usize) {
+        let token = self.tokens.get(&tag_id).unwrap();
+        let mut matches = Vec::new();
+        for index in (0..self.formatting.len()).rev() {
+            let other = match self.formatting[index] {
+                Some(r) => self.tokens.get(&r).unwrap(),
+                None => break,
+            };
+            if other.tag() == token.tag() && other.attributes() == token.attributes() {
+                matches.push(index);
+            }
+        }
+
+        if matches.len() >= 3 {
+            self.formatting.remove(*matches.last().unwrap());
+        }
+        self.formatting.push(Some(tag_id));
+    }
+
+    /// Clear list of active formatting elements up to the last marker
+    fn clear_formatting_to_marker(&mut self) {
+        while let Some(entry) = self.formatting.pop() {
+            if entry.is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Re-open formatting elements that were closed by misnested tags
+    pub(crate) fn reconstruct_formatting(&mut self) {
+        let mut index = match self.formatting.last() {
+            Some(Some(tag_id)) if !self.open.contains(tag_id) => self.formatting.len() - 1,
+            _ => return,
+        };
+
+        // Rewind to the first entry that is not open
+        while index > 0 {
+            match self.formatting[index - 1] {
+                Some(tag_id) if !self.open.contains(&tag_id) => index -= 1,
+                _ => break,
+            }
+        }
+
+        // Create and insert clones
+        for entry in index..self.formatting.len() {
+            let tag_id = self.formatting[entry].unwrap();
+            let tag = self.tag_of(tag_id);
+            let clone_id = self.insert(&tag, Vec::new(), Vec::new(), &false, "");
+            self.copy_attributes(tag_id, clone_id);
+            self.formatting[entry] = Some(clone_id);
+        }
+    }
+
+    /// Copy attributes from one token to another
+    fn copy_attributes(&mut self, from_id: usize, to_id: usize) {
+        let from = self.tokens.get(&from_id).unwrap().clone();
+        self.tokens.get_mut(&to_id).unwrap().copy_attributes(&from);
+    }
+
+    /// Create a clone of token, which is not yet placed within the tree
+    fn clone_element(&mut self, tag_id: usize) -> usize {
+        let tag = self.tag_of(tag_id);
+        self.tag_id += 1;
+        self.depth.entry(tag.clone()).or_default().push(self.tag_id);
+        let token_depth = self.depth.get(&tag).unwrap().len();
+
+        let mut token = Token::new(&self.tag_id, &0, &token_depth, &false, &tag, "", "");
+        token.set_source("");
+        self.tokens.insert(self.tag_id, token);
+        self.copy_attributes(tag_id, self.tag_id);
+        self.tag_id
+    }
+
+    /// Move token to be the last child of a new parent
+    fn reparent(&mut self, tag_id: usize, parent_id: usize) {
+        let old_parent = self.tokens.get(&tag_id).unwrap().parent_id();
+        if let Some(children) = self.stack.get_mut(&old_parent) {
+            children.retain(|id| *id != tag_id);
+        }
+        self.stack.entry(parent_id).or_default().push(tag_id);
+        self.tokens.get_mut(&tag_id).unwrap().set_parent_id(&parent_id);
+    }
+
+    /// Mark element as closed by the adoption agency, without its own closing tag
+    fn mark_adopted(&mut self, tag_id: usize, span: &Span) {
+        let tag = self.tag_of(tag_id);
+        if let Some(ids) = self.depth.get_mut(&tag) {
+            ids.retain(|id| *id != tag_id);
+            if ids.is_empty() {
+                self.depth.remove(&tag);
+            }
+        }
+        let token = self.tokens.get_mut(&tag_id).unwrap();
+        token.mark_closed();
+        token.set_close_span(span);
+    }
+
+    /// Adoption agency algorithm, handles misnested formatting elements such as `<b><i></b></i>`.
+    /// Returns false if the end tag should instead be handled as any other end tag.
+    fn adoption_agency(&mut self, tag: &str, tag_string: &str) -> bool {
+        // Current node is not a formatting element, simply close it
+        let current = self.current_node();
+        if self.tag_of(current) == tag && !self.formatting.contains(&Some(current)) {
+            self.pop(tag_string);
+            return true;
+        }
+
+        for round in 0..8 {
+            // Get formatting element
+            let list_index = match self.formatting_index(tag) {
+                Some(r) => r,
+                None => return false,
+            };
+            let formatting_id = self.formatting[list_index].unwrap();
+            let pos = match self.open.iter().position(|id| *id == formatting_id) {
+                Some(r) => r,
+                None => {
+                    self.formatting.remove(list_index);
+                    self.ignore_end_tag(tag, tag_string);
+                    return true;
+                }
+            };
+            if self.in_scope(&[tag], Scope::Default) != Some(pos) {
+                self.ignore_end_tag(tag, tag_string);
+                return true;
+            }
+
+            // Get furthest block, if none simply close the formatting element
+            let block_pos = match (pos + 1..self.open.len()).find(|p| is_special(&self.tag_of(self.open[*p]))) {
+                Some(r) => r,
+                None => {
+                    self.close_at(pos, tag_string);
+                    self.formatting.retain(|entry| *entry != Some(formatting_id));
+                    return true;
+                }
+            };
+            let block_id = self.open[block_pos];
+            let ancestor_id = if pos > 0 { self.open[pos - 1] } else { 0 };
+            if round == 0 && !tag_string.is_empty() {
+                self.error(ParseErrorKind::MisnestedTag(tag.to_string()), &self.end_tag_span(tag_string));
+            }
+
+            // Go through elements between formatting element and furthest block
+            let mut bookmark: Option<usize> = None;
+            let mut node_pos = block_pos;
+            let mut last_node = block_id;
+            let mut closed: Vec<usize> = Vec::new();
+            let mut clones: Vec<usize> = Vec::new();
+            let mut counter = 0;
+            loop {
+                counter += 1;
+                node_pos -= 1;
+                let node_id = self.open[node_pos];
+                if node_id == formatting_id {
+                    break;
+                }
+
+                // Remove from list of active formatting elements, if needed
+                let mut node_index = self.formatting.iter().position(|entry| *entry == Some(node_id));
+                if counter > 3 && node_index.is_some() {
+                    self.formatting.remove(node_index.unwrap());
+                    node_index = None;
+                }
+
+                // Node is closed in place, and replaced by a clone if it is a formatting element
+                closed.push(node_id);
+                self.open.remove(node_pos);
+                if let Some(index) = node_index {
+                    let clone_id = self.clone_element(node_id);
+                    self.formatting[index] = Some(clone_id);
+                    self.open.insert(node_pos, clone_id);
+                    if last_node == block_id {
+                        bookmark = Some(clone_id);
+                    }
+                    self.reparent(last_node, clone_id);
+                    clones.push(clone_id);
+                    last_node = clone_id;
+                }
+            }
+
+            // Move furthest block, or the outermost clone around it, after the formatting element
+            self.reparent(last_node, ancestor_id);
+
+            // Move children of furthest block into a clone of the formatting element
+            let new_id = self.clone_element(formatting_id);
+            let children = self.stack.remove(&block_id).unwrap_or_default();
+            for child_id in children.iter() {
+                self.tokens.get_mut(child_id).unwrap().set_parent_id(&new_id);
+            }
+            self.stack.insert(new_id, children);
+            self.stack.insert(block_id, vec![new_id]);
+            self.tokens.get_mut(&new_id).unwrap().set_parent_id(&block_id);
+
+            // Update list of active formatting elements and open elements
+            self.formatting.retain(|entry| *entry != Some(formatting_id));
+            let index = match bookmark {
+                Some(clone_id) => self.formatting.iter().position(|entry| *entry == Some(clone_id)).unwrap() + 1,
+                None => list_index.min(self.formatting.len()),
+            };
+            self.formatting.insert(index, Some(new_id));
+            self.open.retain(|id| *id != formatting_id);
+            let block_pos = self.open.iter().position(|id| *id == block_id).unwrap();
+            self.open.insert(block_pos + 1, new_id);
+
+            // Update code, closing elements before the furthest block and opening the clones
+            let block_start = self.tokens.get(&block_id).unwrap().span().start;
+            let span = Span::new(block_start, block_start);
+            closed.push(formatting_id);
+            let mut before = String::new();
+            for tag_id in closed.iter() {
+                self.mark_adopted(*tag_id, &span);
+                before.push_str(&format!("</parsex{}>", tag_id));
+            }
+            for tag_id in clones.iter().rev().chain([new_id].iter()) {
+                self.tokens.get_mut(tag_id).unwrap().set_span(&span);
+            }
+            for clone_id in clones.iter().rev() {
+                before.push_str(&format!("<parsex{}>", clone_id));
+            }
+            let block_tag = format!("<parsex{}>", block_id);
+            let start = self.code.find(&block_tag).unwrap();
+            let after = format!("<parsex{}>", new_id);
+            self.code.insert_str(start + block_tag.len(), &after);
+            self.code.insert_str(start, &before);
+            self.cursor += before.len() + after.len();
+        }
+
+        self.skip(tag_string);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_html, parse_html_with_diagnostics, ParseErrorKind, Stack};
+
+    /// Get outline of tree below token, as tag(children) for elements and the text of text nodes
+    fn outline(stack: &Stack, token_id: usize) -> String {
+        let mut res = String::new();
+        for child_id in stack.child_ids(&token_id) {
+            let token = stack.get(child_id).unwrap();
+            if token.is_element() {
+                res.push_str(&format!("{}({})", token.tag(), outline(stack, *child_id)));
+            } else {
+                res.push_str(&token.contents());
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn implied_end_tags() {
+        assert_eq!(outline(&parse_html("<p>one<p>two<div>three</div>"), 0), "p(one)p(two)div(three)");
+        assert_eq!(outline(&parse_html("<p>one<ul><li>two</ul>"), 0), "p(one)ul(li(two))");
+        assert_eq!(outline(&parse_html("<button><p>a<button>b"), 0), "button(p(a))button(b)");
+        assert_eq!(outline(&parse_html("<ul><li>a<li>b<ul><li>c</ul><li>d</ul>"), 0), "ul(li(a)li(bul(li(c)))li(d))");
+        assert_eq!(outline(&parse_html("<dl><dt>a<dd>b<dt>c</dl>"), 0), "dl(dt(a)dd(b)dt(c))");
+        assert_eq!(
+            outline(&parse_html("<table><tr><td>1<td>2<tr><th>3</table>"), 0),
+            "table(tr(td(1)td(2))tr(th(3)))"
+        );
+        assert_eq!(outline(&parse_html("<select><option>a<option>b</select>"), 0), "select(option(a)option(b))");
+        assert_eq!(outline(&parse_html("<h1>a<h2>b"), 0), "h1(a)h2(b)");
+    }
+
+    #[test]
+    fn implied_end_tags_are_not_errors() {
+        let (stack, errors) = parse_html_with_diagnostics("<ul><li>a<li>b</ul><p>c<p>d");
+        assert!(errors.is_empty());
+        assert_eq!(stack.render(), "<ul><li>a<li>b</ul><p>c<p>d");
+    }
+
+    #[test]
+    fn adoption_agency_for_misnested_formatting() {
+        let html = "<b>1<p>2</b>3</p>";
+        let (stack, errors) = parse_html_with_diagnostics(html);
+        assert_eq!(outline(&stack, 0), "b(1)p(b(2)3)");
+        assert_eq!(stack.render(), html);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind(), ParseErrorKind::MisnestedTag("b".to_string()));
+        assert_eq!((errors[0].span().start, errors[0].span().end), (8, 12));
+
+        assert_eq!(outline(&parse_html("<b>1<i>2</b>3</i>4"), 0), "b(1i(2))i(3)4");
+        assert_eq!(outline(&parse_html("<a href=1>x<a href=2>y"), 0), "a(x)a(y)");
+    }
+
+    #[test]
+    fn stray_end_tags_are_ignored() {
+        let (stack, errors) = parse_html_with_diagnostics("<p>a</div>b</p>");
+        assert_eq!(outline(&stack, 0), "p(ab)");
+        assert_eq!(errors[0].kind(), ParseErrorKind::UnexpectedEndTag("div".to_string()));
+        assert_eq!((errors[0].span().start, errors[0].span().end), (4, 10));
+    }
+
+    #[test]
+    fn trailing_solidus_on_non_void_element_is_ignored() {
+        let (stack, errors) = parse_html_with_diagnostics("<div/>after<br/><svg><path/></svg>");
+        let div = stack.query().tag("div").to_vec()[0];
+        assert_eq!(stack.get_text(&div.id()), "after");
+        assert_eq!(stack.query().tag("br").to_vec()[0].parent_id(), div.id());
+        assert!(stack.query().tag("path").to_vec()[0].is_self_closing());
+        assert_eq!(stack.render(), "<div/>after<br/><svg><path/></svg>");
+
+        assert_eq!(errors[0].kind(), ParseErrorKind::NonVoidSelfClosingTag("div".to_string()));
+        assert_eq!((errors[0].span().start, errors[0].span().end), (0, 6));
+        assert!(!errors.iter().any(|error| matches!(error.kind(), ParseErrorKind::NonVoidSelfClosingTag(tag) if tag != "div")));
+        assert!(parse_html_with_diagnostics("<svg/>").1.is_empty());
+        assert_eq!(parse_html("<p/>one<p>two").query().tag("p").to_vec().len(), 2);
+    }
+}
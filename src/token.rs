@@ -1,21 +1,43 @@
-
-use regex::Regex;
-use super::parse_attr;
+use super::parse_attributes;
+use crate::attribute::Attribute;
+use crate::doctype::Doctype;
+use crate::elements::{is_raw_text, is_void};
+use crate::entities::decode;
 use crate::query::Query;
-use crate::stack::Stack;
-use std::collections::HashMap;
+use crate::selector::Selector;
+use crate::span::Span;
+use crate::stack::{Stack, PLACEHOLDER};
+use crate::token_mut::TokenMut;
+
+/// Kind of node a token represents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Element,
+    Text,
+    Comment,
+    Doctype,
+    CData,
+    ProcessingInstruction,
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     id: usize,
+    kind: NodeKind,
     parent_id: usize,
     is_closed: bool,
     is_self_closing: bool,
     depth: usize,
     tag: String,
-    attributes: HashMap<String, String>,
+    attributes: Vec<Attribute>,
     attr_extra: String,
+    duplicate_attrs: Vec<String>,
     contents: String,
+    source: String,
+    close_source: String,
+    span: Span,
+    close_span: Option<Span>,
+    is_dirty: bool,
 }
 
 impl Token {
@@ -29,26 +51,59 @@ impl Token {
         attr_string: &str,
         contents: &str,
     ) -> Self {
-        let (attr, attr_extra) = parse_attr(attr_string);
+        let (attributes, duplicate_attrs) = parse_attributes(attr_string);
+        let mut token = Self::element(id, parent_id, depth, is_self_closing, tag, attributes, duplicate_attrs);
+        token.contents = contents.to_string();
+        token
+    }
 
+    /// Create new element token with attributes that are already parsed
+    pub(crate) fn element(
+        id: &usize,
+        parent_id: &usize,
+        depth: &usize,
+        is_self_closing: &bool,
+        tag: &str,
+        attributes: Vec<Attribute>,
+        duplicate_attrs: Vec<String>,
+    ) -> Self {
         Self {
             id: *id,
+            kind: NodeKind::Element,
             parent_id: *parent_id,
             is_closed: false,
-            is_self_closing: is_self_closing.clone(),
+            is_self_closing: *is_self_closing,
             depth: *depth,
             tag: tag.to_string(),
-            attributes: attr,
-            attr_extra,
-            contents: contents.to_string(),
+            attributes,
+            attr_extra: String::new(),
+            duplicate_attrs,
+            contents: String::new(),
+            source: String::new(),
+            close_source: String::new(),
+            span: Span::default(),
+            close_span: None,
+            is_dirty: true,
         }
     }
 
+    /// Create new token for node other than an element (eg. text, comment), with contents as written within the source
+    pub fn node(id: &usize, parent_id: &usize, kind: NodeKind, contents: &str) -> Self {
+        let mut token = Self::new(id, parent_id, &0, &false, "", "", contents);
+        token.kind = kind;
+        token
+    }
+
     /// Get token id
     pub fn id(&self) -> usize {
         self.id
     }
 
+    /// Get kind of node
+    pub fn kind(&self) -> NodeKind {
+        self.kind
+    }
+
     /// Get token's parent id
     pub fn parent_id(&self) -> usize {
         self.parent_id
@@ -64,18 +119,23 @@ impl Token {
         self.is_self_closing
     }
 
+    /// Get if token is a void element (eg. br, img, input)
+    pub fn is_void(&self) -> bool {
+        is_void(&self.tag)
+    }
+
     // Get depth
     pub fn depth(&self) -> usize {
         self.depth
     }
 
-    /// Get name of HTML tag
+    /// Get name of HTML tag, blank if not an element
     pub fn tag(&self) -> String {
         self.tag.clone()
     }
 
-    /// Get attributes
-    pub fn attributes(&self) -> HashMap<String, String> {
+    /// Get attributes, in the order they were written
+    pub fn attributes(&self) -> Vec<Attribute> {
         self.attributes.clone()
     }
 
@@ -84,73 +144,193 @@ impl Token {
         self.attr_extra.clone()
     }
 
-    /// Get single attribute value
+    /// Get names of attributes that appeared more than once within opening tag, only the first occurrence is kept
+    pub fn duplicate_attrs(&self) -> Vec<String> {
+        self.duplicate_attrs.clone()
+    }
+
+    /// Get single attribute value, with character references decoded
     pub fn attr(&self, key: &str) -> Option<String> {
-        let res = match self.attributes.get(&key.to_string()) {
+        let res = match self.find_attr(key) {
             Some(r) => r,
             None => {
                 return None;
             }
         };
-        Some(res.clone())
+        Some(res.value())
+    }
+
+    /// Get single attribute value as written within the source, without decoding character references
+    pub fn raw_attr(&self, key: &str) -> Option<String> {
+        self.find_attr(key).map(|attr| attr.raw_value())
     }
 
     /// Check if attribute has key, and if key equals to value
     pub fn attr_equals(&self, key: &str, value: &str) -> bool {
-        if (!self.attributes.contains_key(&key.to_string()))
-            || (self.attributes.get(&key.to_string()).unwrap() != value)
-        {
-            return false;
+        match self.find_attr(key) {
+            Some(attr) => attr.value() == value,
+            None => false,
         }
-        true
     }
 
     /// Check if attribute has key, and if key contans value
     pub fn attr_contains(&self, key: &str, value: &str) -> bool {
-        if (!self.attributes.contains_key(&key.to_string()))
-            || (!self.attributes.get(&key.to_string()).unwrap().contains(&value))
-        {
-            return false;
+        match self.find_attr(key) {
+            Some(attr) => attr.value().contains(value),
+            None => false,
         }
-        true
     }
 
     /// Check if attribute has key, and if key contains value
     pub fn attr_has_segment(&self, key: &str, value: &str) -> bool {
-        let input = match self.attributes.get(&key.to_string()) {
-            Some(r) => r,
+        let input = match self.find_attr(key) {
+            Some(r) => r.value(),
             None => return false
         };
 
-        let chk: Vec<&str> = input.split(" ").collect();
+        let chk: Vec<&str> = input.split_ascii_whitespace().collect();
         chk.contains(&value)
     }
 
     // Check if has attribute
     pub fn has_attr(&self, key: &str) -> bool {
-        self.attributes.contains_key(&key.to_string())
+        self.find_attr(key).is_some()
+    }
+
+    /// Find attribute by name
+    fn find_attr(&self, key: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|attr| attr.is_named(key))
+    }
+
+    /// Get original opening tag as written within the source, blank if tag was implied
+    pub fn source(&self) -> String {
+        self.source.clone()
+    }
+
+    /// Get original closing tag as written within the source, blank if tag was implied
+    pub fn close_source(&self) -> String {
+        self.close_source.clone()
     }
 
-    /// Get contents between start and closing tags.  Blank string if tag not closed.
+    /// Get byte span of opening tag within the source
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Get byte span of closing tag within the source, empty if implied and none if not closed
+    pub fn close_span(&self) -> Option<Span> {
+        self.close_span
+    }
+
+    /// Get byte span of contents between opening and closing tags within the source, none if not closed
+    pub fn contents_span(&self) -> Option<Span> {
+        self.close_span.map(|close| Span::new(self.span.end, close.start.max(self.span.end)))
+    }
+
+    /// Get if token has been modified since parsed, and must be re-serialized when rendering
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty
+    }
+
+    /// Get contents with character references decoded.  This is the node as written for text, comment, doctype, CDATA and
+    /// processing instruction nodes, and the body of raw text and RCDATA elements (eg. script, title).  Other elements keep
+    /// their contents as child tokens on the stack so this is blank for them, use `Stack::render_tag()` for their inner HTML
+    /// or `Stack::get_text()` for their text.
     pub fn contents(&self) -> String {
+        self.decode(&self.raw_contents())
+    }
+
+    /// Get contents between start and closing tags as written, without decoding character references.  Text nodes keep surrounding whitespace.
+    pub fn raw_contents(&self) -> String {
+        if self.kind == NodeKind::Text {
+            return self.contents.clone();
+        }
         self.contents.trim().to_string()
     }
 
+    /// Get body of raw text and RCDATA elements as written, including surrounding whitespace
+    pub(crate) fn raw_body(&self) -> String {
+        self.contents.clone()
+    }
+
+    /// Get if token is a text node
+    pub fn is_text(&self) -> bool {
+        self.kind == NodeKind::Text
+    }
+
+    /// Get if token is an element, and not a text, comment, doctype, CDATA or processing instruction node
+    pub fn is_element(&self) -> bool {
+        self.kind == NodeKind::Element
+    }
+
+    /// Get doctype, if token is a doctype node
+    pub fn doctype(&self) -> Option<Doctype> {
+        if self.kind != NodeKind::Doctype {
+            return None;
+        }
+        Some(Doctype::parse(&self.contents))
+    }
+
+    /// Get text inside comment, without the surrounding `<!--` and `-->`.  None if token is not a comment.
+    pub fn comment_text(&self) -> Option<String> {
+        if self.kind != NodeKind::Comment {
+            return None;
+        }
+
+        // Abruptly closed empty comments, '<!-->' and '<!--->'
+        let body = match self.contents.strip_prefix("<!--") {
+            Some(">") | Some("->") => "",
+            Some(body) => body.strip_suffix("-->").or(body.strip_suffix("--!>")).unwrap_or(body),
+            None => {
+                // Bogus comment, eg. '<!foo>' or '</ foo>'
+                let body = self.contents.get(2..).unwrap_or("");
+                body.strip_suffix('>').unwrap_or(body)
+            }
+        };
+        Some(body.to_string())
+    }
+
+    /// Set text inside comment, ignored if token is not a comment
+    pub fn set_comment_text(&mut self, text: &str) {
+        if self.kind == NodeKind::Comment {
+            self.replace_contents(&format!("<!--{}-->", text));
+        }
+    }
+
+    /// Get text inside CDATA section, without the surrounding `<![CDATA[` and `]]>`.  None if token is not a CDATA section.
+    pub fn cdata_text(&self) -> Option<String> {
+        if self.kind != NodeKind::CData {
+            return None;
+        }
+        let body = self.contents.get(9..).unwrap_or("");
+        Some(body.strip_suffix("]]>").unwrap_or(body).to_string())
+    }
+
     /// Get contents with all HTML tags stripped
     pub fn strip_tags(&self) -> String {
-        let re = Regex::new(r"<([\/]?)parsex(\d+)>").unwrap();
-        re.replace_all(&self.contents, "").to_string()
+        self.decode(&PLACEHOLDER.replace_all(&self.contents, ""))
     }
 
+    /// Decode character references within text, except for comments and raw text elements (eg. script, style)
+    fn decode(&self, text: &str) -> String {
+        if !(self.is_text() || self.is_element()) || is_raw_text(&self.tag) {
+            return text.to_string();
+        }
+        decode(text)
+    }
 
+    /// Check if token matches pre-compiled CSS selector, using stack to look up its ancestors and siblings
+    pub fn matches(&self, selector: &Selector, stack: &Stack) -> bool {
+        selector.matches(stack, &self.id)
+    }
 
-    /// Get mutable instance of tag
-    pub fn as_mut<'a>(&self, stack: &'a mut Stack) -> &'a mut Token {
+    /// Get mutable instance of tag, changes to its contents are made through the stack
+    pub fn as_mut<'a>(&self, stack: &'a mut Stack) -> TokenMut<'a> {
         stack.get_mut(&self.id).unwrap()
     }
 
     /// Get children, returns query so must call .iter() or .to_vec() on results.
-    pub fn children<'b>(&'b self, stack: &'b mut Stack) -> Query {
+    pub fn children<'b>(&self, stack: &'b Stack) -> Query<'b> {
         stack.get_children(&self.id)
     }
 
@@ -167,43 +347,249 @@ impl Token {
     /// Mark self closing
     pub fn mark_self_closing(&mut self) {
         self.is_self_closing = true;
+        self.is_dirty = true;
     }
 
     /// Mark mpt self closing
     pub fn mark_not_self_closing(&mut self) {
         self.is_self_closing = false;
+        self.is_dirty = true;
+    }
+
+    /// Mark as modified, so it is re-serialized when rendering
+    pub fn mark_dirty(&mut self) {
+        self.is_dirty = true;
+    }
+
+    /// Set original opening tag as parsed, marks token as unmodified
+    pub(crate) fn set_source(&mut self, source: &str) {
+        self.source = source.to_string();
+        self.is_dirty = false;
+    }
+
+    /// Set original closing tag as parsed
+    pub(crate) fn set_close_source(&mut self, close_source: &str) {
+        self.close_source = close_source.to_string();
+    }
+
+    /// Set byte span of opening tag within the source
+    pub(crate) fn set_span(&mut self, span: &Span) {
+        self.span = *span;
+    }
+
+    /// Set byte span of closing tag within the source
+    pub(crate) fn set_close_span(&mut self, span: &Span) {
+        self.close_span = Some(*span);
+    }
+
+    /// Copy attributes from another token, without marking as modified
+    pub(crate) fn copy_attributes(&mut self, other: &Token) {
+        self.attributes = other.attributes.clone();
+        self.attr_extra = other.attr_extra.clone();
+    }
+
+    /// Set parent id, when token is moved within the stack
+    pub(crate) fn set_parent_id(&mut self, parent_id: &usize) {
+        self.parent_id = *parent_id;
+    }
+
+    /// Set id, when token is added to a stack
+    pub(crate) fn set_id(&mut self, id: &usize) {
+        self.id = *id;
+    }
+
+    /// Set depth, when token is moved within the stack
+    pub(crate) fn set_depth(&mut self, depth: &usize) {
+        self.depth = *depth;
     }
 
     /// Set tag name
     pub fn set_tag(&mut self, tag_name: &str) {
         self.tag = tag_name.to_string();
+        self.is_dirty = true;
     }
 
-    /// Update existing attribute value, or add new attribute if not exists
+    /// Update existing attribute value, or add new attribute to the end if not exists
     pub fn set_attr(&mut self, key: &str, value: &str) {
-        *self
-            .attributes
-            .entry(key.to_string())
-            .or_insert(value.to_string()) = value.to_string();
+        match self.attributes.iter_mut().find(|attr| attr.is_named(key)) {
+            Some(attr) => attr.set_value(value),
+            None => self.attributes.push(Attribute::new(key, value)),
+        }
+        self.is_dirty = true;
     }
 
     /// Delete attribute
     pub fn del_attr(&mut self, key: &str) {
-        self.attributes.remove(&key.to_string());
+        self.attributes.retain(|attr| !attr.is_named(key));
+        self.is_dirty = true;
     }
 
     /// Purge all attributes
     pub fn purge_attr(&mut self) {
         self.attributes.clear();
+        self.is_dirty = true;
     }
 
     /// Update extra non-attribute text within opening tag
     pub fn set_attr_extra(&mut self, extra: &str) {
         self.attr_extra = extra.to_string();
+        self.is_dirty = true;
     }
-    /// Set contents between start and closing tags.
+    /// Set contents of token only.  Text, comment and other non-element nodes are rendered from their contents, but elements
+    /// keep their contents as child tokens on the stack, so this does not change how they render.
+    #[deprecated(note = "use `TokenMut::set_contents()` via `Stack::get_mut()`, or `Stack::set_inner_html()`, which update the stack")]
     pub fn set_contents(&mut self, contents: &str) {
+        self.replace_contents(contents);
+    }
+
+    /// Set contents field, marking non-element nodes as modified.  Elements must be changed through
+    /// `Stack::set_inner_html()` or `Stack::set_text()` so the tree is kept in sync.
+    pub(crate) fn replace_contents(&mut self, contents: &str) {
         self.contents = contents.to_string();
+        if !self.is_element() {
+            self.is_dirty = true;
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{parse_html, parse_html_with_diagnostics, NodeKind, ParseErrorKind, Token};
+
+    #[test]
+    fn contents_of_elements_are_kept_on_the_stack() {
+        let stack = parse_html("<div>a &amp; <b>b</b></div><title>x &lt; y</title><!-- note -->");
+        let div = stack.query().tag("div").to_vec()[0];
+        assert_eq!(div.contents(), "");
+        assert_eq!(stack.render_tag(&div.id()), "a &amp; <b>b</b>");
+        assert_eq!(stack.get_text(&div.id()), "a & b");
+
+        assert_eq!(stack.query().tag("title").to_vec()[0].contents(), "x < y");
+        let comment = stack.query().kind(NodeKind::Comment).to_vec()[0];
+        assert_eq!(comment.contents(), "<!-- note -->");
+    }
+
+    #[test]
+    fn text_nodes_between_elements() {
+        let html = "<p>\n  one <b>two</b> three&amp;\n</p> after";
+        let stack = parse_html(html);
+        let p = stack.query().tag("p").to_vec()[0].id();
+        let texts: Vec<(String, usize)> =
+            stack.query().kind(NodeKind::Text).to_vec().iter().map(|text| (text.raw_contents(), text.parent_id())).collect();
+        let b = stack.query().tag("b").to_vec()[0].id();
+        assert_eq!(
+            texts,
+            [
+                ("\n  one ".to_string(), p),
+                ("two".to_string(), b),
+                (" three&amp;\n".to_string(), p),
+                (" after".to_string(), 0)
+            ]
+        );
+        assert_eq!(stack.get_text(&p), "\n  one two three&\n");
+        assert_eq!(stack.render(), html);
+    }
+
+    #[test]
+    fn text_node_kind_and_contents() {
+        let stack = parse_html("<div>a &lt; b</div><script>x &lt; y</script>");
+        let text = stack.query().kind(NodeKind::Text).to_vec();
+        assert_eq!(text.len(), 1);
+        assert!(text[0].is_text() && !text[0].is_element());
+        assert_eq!(text[0].tag(), "");
+        assert_eq!(text[0].contents(), "a < b");
+        assert_eq!(text[0].raw_contents(), "a &lt; b");
+        assert_eq!(stack.query().tag("script").to_vec()[0].contents(), "x &lt; y");
+    }
+
+    #[test]
+    fn set_text_replaces_children_with_one_text_node() {
+        let mut stack = parse_html("<p>one <b>two</b></p>");
+        let p = stack.query().tag("p").to_vec()[0].id();
+        stack.set_text(&p, "<i>&");
+        assert_eq!(stack.render(), "<p>&lt;i&gt;&amp;</p>");
+        assert!(stack.query().tag("b").to_vec().is_empty());
+
+        let text = stack.query().kind(NodeKind::Text).to_vec()[0].id();
+        assert_eq!(stack.get(&text).unwrap().parent_id(), p);
+        stack.get_mut(&text).unwrap().set_text("x > y");
+        assert_eq!(stack.render(), "<p>x &gt; y</p>");
+    }
+
+    #[test]
+    fn cdata_and_processing_instruction_nodes() {
+        let html = "<?xml version=\"1.0\"?><svg><![CDATA[a < b && <c>]]></svg><? bogus >";
+        let (stack, errors) = parse_html_with_diagnostics(html);
+        assert!(errors.is_empty());
+
+        let cdata = stack.query().kind(NodeKind::CData).to_vec()[0];
+        assert_eq!(cdata.cdata_text(), Some("a < b && <c>".to_string()));
+        assert_eq!(cdata.parent_id(), stack.query().tag("svg").to_vec()[0].id());
+        assert!(stack.query().tag("c").to_vec().is_empty());
+
+        let pi: Vec<String> =
+            stack.query().kind(NodeKind::ProcessingInstruction).to_vec().iter().map(|token| token.contents()).collect();
+        assert_eq!(pi, ["<?xml version=\"1.0\"?>", "<? bogus >"]);
+        assert_eq!(stack.render(), html);
+
+        let stack = parse_html("<?x><p>hi</p>?>");
+        assert_eq!(stack.query().kind(NodeKind::ProcessingInstruction).to_vec()[0].contents(), "<?x>");
+        let p = stack.query().tag("p").to_vec()[0].id();
+        assert_eq!(stack.get_text(&p), "hi");
+        assert_eq!(stack.render(), "<?x><p>hi</p>?>");
+    }
+
+    #[test]
+    fn unterminated_doctype_and_cdata() {
+        let (stack, errors) = parse_html_with_diagnostics("<![CDATA[open");
+        assert_eq!(stack.query().kind(NodeKind::CData).to_vec()[0].cdata_text(), Some("open".to_string()));
+        assert_eq!(errors[0].kind(), ParseErrorKind::EofInCData);
+
+        let (stack, errors) = parse_html_with_diagnostics("<!DOCTYPE html");
+        assert_eq!(stack.doctype().unwrap().name(), "html");
+        assert_eq!(errors[0].kind(), ParseErrorKind::EofInDoctype);
+    }
+
+    #[test]
+    fn node_kinds() {
+        let stack = parse_html("<!DOCTYPE html><!-- a --><p>b</p><![CDATA[c]]><?d?>");
+        let kinds: Vec<NodeKind> = stack.iter().map(|token| token.kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                NodeKind::Doctype,
+                NodeKind::Comment,
+                NodeKind::Element,
+                NodeKind::Text,
+                NodeKind::CData,
+                NodeKind::ProcessingInstruction
+            ]
+        );
+        assert!(stack.iter().filter(|token| token.kind() != NodeKind::Element).all(|token| token.tag().is_empty()));
+        assert!(stack.query().tag("!").to_vec().is_empty());
+        assert_eq!(stack.query().kind(NodeKind::Element).to_vec().len(), 1);
+    }
+
+    #[test]
+    fn comment_text() {
+        let stack = parse_html("<!-- a --><!----><!--><!-- b --!><!c></ d><p>");
+        let comments: Vec<Option<String>> =
+            stack.query().kind(NodeKind::Comment).to_vec().iter().map(|token| token.comment_text()).collect();
+        let expected = [" a ", "", "", " b ", "c", " d"].map(|text| Some(text.to_string()));
+        assert_eq!(comments, expected);
+        assert_eq!(stack.query().tag("p").to_vec()[0].comment_text(), None);
+    }
+
+    #[test]
+    fn set_comment_text() {
+        let mut stack = parse_html("<p><!-- old --></p>");
+        let id = stack.query().kind(NodeKind::Comment).to_vec()[0].id();
+        stack.get_mut(&id).unwrap().set_comment_text(" new ");
+        assert_eq!(stack.render(), "<p><!-- new --></p>");
+
+        let mut token = Token::node(&0, &0, NodeKind::Text, "text");
+        token.set_comment_text("x");
+        assert_eq!(token.contents(), "text");
+        assert_eq!(Token::node(&0, &0, NodeKind::Comment, "<!--x-->").comment_text(), Some("x".to_string()));
+    }
+}
@@ -1,38 +1,81 @@
+use crate::cursor::Cursor;
+use crate::query::SearchCriteria;
+use crate::selector::SiblingIndex;
+use crate::stack::Stack;
 use crate::token::Token;
 
-pub struct TokenIter {
-    tokens: Vec<Token>,
-    position: usize,
+/// Lazy iterator over tokens matching search criteria, walking the stack only as far as needed
+pub struct TokenIter<'a> {
+    stack: &'a Stack,
+    cursor: Cursor,
+    criteria: SearchCriteria,
+    siblings: SiblingIndex,
 }
 
-impl TokenIter {
-    pub fn new(tokens: &[Token]) -> Self {
-        Self {
-            tokens: tokens.to_owned(),
-            position: 0,
-        }
+impl<'a> TokenIter<'a> {
+    pub fn new(stack: &'a Stack, criteria: SearchCriteria) -> Self {
+        Self { stack, cursor: criteria.cursor(), criteria, siblings: SiblingIndex::default() }
     }
-
-    // Return next token in iteration, but as mutable.
-    //fn next_mut(&mut self) -> Option<&mut Token> {
-    //if let Some(token) = self.next() {
-    //let res = self.stack.fetch_mut(&token.id()).unwrap();
-    //return Some(res);
-    //}
-    //None
-    //}
 }
 
-impl Iterator for TokenIter {
-    type Item = Token;
+impl<'a> Iterator for TokenIter<'a> {
+    type Item = &'a Token;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.position >= self.tokens.len() {
-            return None;
+        while let Some(token_id) = self.cursor.next_id(self.stack) {
+            let token = self.stack.tokens.get(&token_id).unwrap();
+            if self.criteria.matches_with(self.stack, token, &self.siblings) {
+                return Some(token);
+            }
         }
-        let token = self.tokens.get(self.position).unwrap();
-        self.position += 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_html, NodeKind};
+    use std::ptr;
+
+    #[test]
+    fn walks_only_as_far_as_needed() {
+        let html: String = (0..1000).map(|i| format!("<li>{}</li>", i)).collect();
+        let stack = parse_html(&format!("<ul>{}</ul>", html));
+        let li = stack.query().tag("li").ids();
+
+        let mut iter = stack.query().tag("li").iter();
+        assert_eq!(iter.next().map(|token| token.id()), Some(li[0]));
+        assert_eq!(iter.cursor.position(), li[0]);
+        assert_eq!(iter.nth(1).map(|token| token.id()), Some(li[2]));
+        assert_eq!(iter.cursor.position(), li[2]);
+
+        let mut iter = stack.select("li:nth-child(5)").iter();
+        assert!(iter.next().is_some());
+        assert_eq!(iter.cursor.position(), li[4]);
+
+        let mut iter = stack.query().kind(NodeKind::Text).iter();
+        assert!(iter.any(|text| text.contents() == "10"));
+        assert_eq!(stack.get(&iter.cursor.position()).unwrap().parent_id(), li[10]);
+    }
+
+    #[test]
+    fn yields_tokens_borrowed_from_the_stack() {
+        let stack = parse_html("<p>a</p><p>b</p>");
+        for token in stack.query().tag("p").iter() {
+            assert!(ptr::eq(token, stack.get(&token.id()).unwrap()));
+        }
+        assert_eq!(stack.iter().count(), 4);
+        assert_eq!(stack.query().tag("div").iter().next(), None);
+    }
 
-        Some(token.clone())
+    #[test]
+    fn iterators_are_independent() {
+        let stack = parse_html("<p>a</p><p>b</p><p>c</p>");
+        let mut first = stack.query().tag("p").iter();
+        first.next();
+        let rest: Vec<String> = first.map(|p| stack.get_text(&p.id())).collect();
+        let all: Vec<String> = stack.query().tag("p").iter().map(|p| stack.get_text(&p.id())).collect();
+        assert_eq!(rest, ["b", "c"]);
+        assert_eq!(all, ["a", "b", "c"]);
     }
 }
@@ -0,0 +1,51 @@
+use crate::stack::Stack;
+use crate::token::Token;
+use std::ops::{Deref, DerefMut};
+
+/// Mutable token along with the stack holding it, so changes to its contents are made to the tree and seen by
+/// rendering and later queries.  Dereferences to the token for attributes, tag name and everything else.
+pub struct TokenMut<'a> {
+    stack: &'a mut Stack,
+    token_id: usize,
+}
+
+impl<'a> TokenMut<'a> {
+    /// Instantiate for token id, which must exist within the stack
+    pub(crate) fn new(stack: &'a mut Stack, token_id: usize) -> Self {
+        Self { stack, token_id }
+    }
+
+    /// Set contents as HTML, replacing all children.  Same as `Stack::set_inner_html()`.
+    pub fn set_contents(&mut self, html: &str) -> Vec<usize> {
+        self.stack.set_inner_html(&self.token_id, html)
+    }
+
+    /// Set contents as plain text, replacing all children.  Same as `Stack::set_text()`.
+    pub fn set_text(&mut self, text: &str) {
+        self.stack.set_text(&self.token_id, text);
+    }
+
+    /// Get contents as HTML, including any changes
+    pub fn inner_html(&self) -> String {
+        self.stack.render_tag(&self.token_id)
+    }
+
+    /// Get text of token and all its descendants
+    pub fn text(&self) -> String {
+        self.stack.get_text(&self.token_id)
+    }
+}
+
+impl Deref for TokenMut<'_> {
+    type Target = Token;
+
+    fn deref(&self) -> &Token {
+        self.stack.tokens.get(&self.token_id).unwrap()
+    }
+}
+
+impl DerefMut for TokenMut<'_> {
+    fn deref_mut(&mut self) -> &mut Token {
+        self.stack.tokens.get_mut(&self.token_id).unwrap()
+    }
+}
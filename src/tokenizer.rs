@@ -0,0 +1,869 @@
+use crate::attribute::{Attribute, QuoteStyle};
+use crate::elements::{is_raw_text, is_rcdata};
+use crate::entities::{invalid_attr_references, invalid_references};
+use crate::error::{ParseError, ParseErrorKind};
+use crate::span::Span;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/// Raw token emitted by the tokenizer, before it is placed onto the stack
+#[derive(Debug, Clone, PartialEq)]
+pub enum RawToken {
+    Text {
+        span: Range<usize>,
+    },
+    StartTag {
+        name: String,
+        attributes: Vec<Attribute>,
+        duplicates: Vec<String>,
+        attr_span: Range<usize>,
+        self_closing: bool,
+        span: Range<usize>,
+    },
+    EndTag {
+        name: String,
+        span: Range<usize>,
+    },
+    Comment {
+        span: Range<usize>,
+    },
+    Doctype {
+        span: Range<usize>,
+    },
+    CData {
+        span: Range<usize>,
+    },
+    ProcessingInstruction {
+        span: Range<usize>,
+    },
+}
+
+/// Tokenizer states, following the WHATWG tokenization states for attributes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueDoubleQuoted,
+    AttributeValueSingleQuoted,
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+}
+
+/// Result of trying to read markup at a '<' character
+enum Markup {
+    Token(RawToken),
+    Skip(usize),
+    Literal,
+    Eof,
+}
+
+/// Character level HTML tokenizer
+pub struct Tokenizer<'a> {
+    html: &'a str,
+    pos: usize,
+    pending: VecDeque<RawToken>,
+    end_tag: Option<String>,
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Instantiate a new tokenizer over block of HTML code
+    pub fn new(html: &'a str) -> Self {
+        Self {
+            html,
+            pos: 0,
+            pending: VecDeque::new(),
+            end_tag: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Take errors encountered so far
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Record parse error at byte range
+    fn error(&mut self, kind: ParseErrorKind, range: Range<usize>) {
+        self.errors.push(ParseError::new(kind, &Span::new(range.start, range.end)));
+    }
+
+    /// Record any invalid character references within byte range of text or attributes
+    fn check_references(&mut self, range: Range<usize>, is_attr: bool) {
+        let text = &self.html[range.clone()];
+        let invalid = if is_attr { invalid_attr_references(text) } else { invalid_references(text) };
+        for r in invalid {
+            let text = self.html[range.start + r.start..range.start + r.end].to_string();
+            self.error(ParseErrorKind::InvalidCharacterReference(text), range.start + r.start..range.start + r.end);
+        }
+    }
+
+    /// Data state, read text until the next piece of markup
+    fn data(&mut self) -> Option<RawToken> {
+        let bytes = self.html.as_bytes();
+        let start = self.pos;
+        let mut index = start;
+
+        while let Some(offset) = self.html[index..].find('<') {
+            let lt = index + offset;
+            match self.markup(lt) {
+                Markup::Token(token) => {
+                    self.pos = token_end(&token);
+                    self.check_raw_text(&token);
+                    if lt == start {
+                        return Some(token);
+                    }
+                    self.pending.push_back(token);
+                    return Some(RawToken::Text { span: start..lt });
+                }
+                Markup::Skip(end) => {
+                    // Nothing to emit (eg. '</>'), text continues after it
+                    index = end;
+                }
+                Markup::Literal => {
+                    index = lt + 1;
+                }
+                Markup::Eof => {
+                    break;
+                }
+            }
+        }
+
+        // Remainder of document is text
+        self.pos = bytes.len();
+        if start < bytes.len() {
+            Some(RawToken::Text { span: start..bytes.len() })
+        } else {
+            None
+        }
+    }
+
+    /// Raw text and RCDATA states, read text verbatim until the appropriate end tag
+    fn raw_text(&mut self) -> Option<RawToken> {
+        let end_tag = self.end_tag.take().unwrap();
+        let start = self.pos;
+        let mut index = start;
+
+        while let Some(offset) = self.html[index..].find("</") {
+            let lt = index + offset;
+            let name_end = lt + 2 + end_tag.len();
+            let is_match = self.html.len() >= name_end
+                && self.html.as_bytes()[lt + 2..name_end].eq_ignore_ascii_case(end_tag.as_bytes())
+                && matches!(
+                    self.html.as_bytes().get(name_end),
+                    Some(b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' | b'/' | b'>')
+                );
+
+            if is_match {
+                if let Markup::Token(token) = self.tag(lt, lt + 2, true) {
+                    self.pos = token_end(&token);
+                    if lt == start {
+                        return Some(token);
+                    }
+                    self.pending.push_back(token);
+                    return Some(RawToken::Text { span: start..lt });
+                }
+                break;
+            }
+            index = lt + 2;
+        }
+
+        // No end tag, remainder of document is text
+        self.pos = self.html.len();
+        if start < self.html.len() {
+            Some(RawToken::Text { span: start..self.html.len() })
+        } else {
+            None
+        }
+    }
+
+    /// Switch to raw text state if token opens a raw text or RCDATA element
+    fn check_raw_text(&mut self, token: &RawToken) {
+        if let RawToken::StartTag { name, self_closing: false, .. } = token {
+            self.open_element(name);
+        }
+    }
+
+    /// Switch to raw text state if tree builder opened a raw text or RCDATA element, for start tags with a
+    /// trailing solidus the tokenizer can not tell apart from self-closing ones (eg. '<script/>')
+    pub fn open_element(&mut self, name: &str) {
+        if is_raw_text(name) || is_rcdata(name) {
+            self.end_tag = Some(name.to_string());
+        }
+    }
+
+    /// Tag open state, determine what kind of markup starts at '<'
+    fn markup(&mut self, lt: usize) -> Markup {
+        let bytes = self.html.as_bytes();
+        match bytes.get(lt + 1) {
+            Some(c) if c.is_ascii_alphabetic() => self.tag(lt, lt + 1, false),
+            Some(b'/') => match bytes.get(lt + 2) {
+                Some(c) if c.is_ascii_alphabetic() => self.tag(lt, lt + 2, true),
+                Some(b'>') => {
+                    self.error(ParseErrorKind::MissingEndTagName, lt..lt + 3);
+                    Markup::Skip(lt + 3)
+                }
+                Some(_) => {
+                    self.error(ParseErrorKind::InvalidFirstCharacterOfTagName, lt..lt + 2);
+                    self.bogus_comment(lt, lt + 2)
+                }
+                None => {
+                    self.error(ParseErrorKind::EofInTag, lt..lt + 2);
+                    Markup::Literal
+                }
+            },
+            Some(b'!') => self.markup_declaration(lt),
+            Some(b'?') => self.processing_instruction(lt),
+            Some(_) => {
+                self.error(ParseErrorKind::InvalidFirstCharacterOfTagName, lt..lt + 1);
+                Markup::Literal
+            }
+            None => {
+                self.error(ParseErrorKind::EofInTag, lt..lt + 1);
+                Markup::Literal
+            }
+        }
+    }
+
+    /// Markup declaration open state, comments and doctypes
+    fn markup_declaration(&mut self, lt: usize) -> Markup {
+        let rest = &self.html[lt + 2..];
+        if rest.starts_with("--") {
+            return self.comment(lt);
+        }
+
+        if rest.len() >= 7 && rest.as_bytes()[..7].eq_ignore_ascii_case(b"doctype") {
+            let end = match self.html[lt..].find('>') {
+                Some(r) => lt + r + 1,
+                None => {
+                    self.error(ParseErrorKind::EofInDoctype, lt..self.html.len());
+                    self.html.len()
+                }
+            };
+            return Markup::Token(RawToken::Doctype { span: lt..end });
+        }
+
+        // CDATA section, runs through to ']]>'
+        if rest.starts_with("[CDATA[") {
+            let end = match self.html[lt..].find("]]>") {
+                Some(r) => lt + r + 3,
+                None => {
+                    self.error(ParseErrorKind::EofInCData, lt..self.html.len());
+                    self.html.len()
+                }
+            };
+            return Markup::Token(RawToken::CData { span: lt..end });
+        }
+
+        self.error(ParseErrorKind::IncorrectlyOpenedComment, lt..lt + 2);
+        self.bogus_comment(lt, lt + 2)
+    }
+
+    /// Processing instruction (eg. `<?xml version="1.0"?>`), runs through to the first '>' as HTML has no '?>' terminator
+    fn processing_instruction(&mut self, lt: usize) -> Markup {
+        let end = match self.html[lt..].find('>') {
+            Some(r) => lt + r + 1,
+            None => {
+                self.error(ParseErrorKind::EofInTag, lt..self.html.len());
+                self.html.len()
+            }
+        };
+        Markup::Token(RawToken::ProcessingInstruction { span: lt..end })
+    }
+
+    /// Comment states, from '<!--' through to '-->'
+    fn comment(&mut self, lt: usize) -> Markup {
+        let body = lt + 4;
+        let rest = &self.html[body..];
+
+        // Abruptly closed empty comments, '<!-->' and '<!--->'
+        if rest.starts_with('>') {
+            self.error(ParseErrorKind::AbruptClosingOfEmptyComment, lt..body + 1);
+            return Markup::Token(RawToken::Comment { span: lt..body + 1 });
+        } else if rest.starts_with("->") {
+            self.error(ParseErrorKind::AbruptClosingOfEmptyComment, lt..body + 2);
+            return Markup::Token(RawToken::Comment { span: lt..body + 2 });
+        }
+
+        let mut index = body;
+        while let Some(offset) = self.html[index..].find("--") {
+            let dash = index + offset;
+            let after = &self.html[dash + 2..];
+            if after.starts_with('>') {
+                return Markup::Token(RawToken::Comment { span: lt..dash + 3 });
+            } else if after.starts_with("!>") {
+                self.error(ParseErrorKind::IncorrectlyClosedComment, dash..dash + 4);
+                return Markup::Token(RawToken::Comment { span: lt..dash + 4 });
+            }
+            index = dash + 1;
+        }
+
+        // EOF in comment, comment runs to end of document
+        self.error(ParseErrorKind::EofInComment, lt..self.html.len());
+        Markup::Token(RawToken::Comment { span: lt..self.html.len() })
+    }
+
+    /// Bogus comment state, runs through to the next '>'
+    fn bogus_comment(&self, lt: usize, start: usize) -> Markup {
+        let end = match self.html[start..].find('>') {
+            Some(r) => start + r + 1,
+            None => self.html.len(),
+        };
+        Markup::Token(RawToken::Comment { span: lt..end })
+    }
+
+    /// Tag name state for start and end tags, followed by the attribute states
+    fn tag(&mut self, lt: usize, name_start: usize, is_closing: bool) -> Markup {
+        let name_end = match self.html[name_start..].find(is_tag_name_end) {
+            Some(r) => name_start + r,
+            None => {
+                self.error(ParseErrorKind::EofInTag, lt..self.html.len());
+                return Markup::Eof;
+            }
+        };
+
+        // EOF in tag, nothing is emitted
+        let scan = scan_attributes(self.html, name_end);
+        let end = match scan.end {
+            Some(r) => r,
+            None => {
+                self.error(ParseErrorKind::EofInTag, lt..self.html.len());
+                return Markup::Eof;
+            }
+        };
+
+        let name = self.html[name_start..name_end].to_ascii_lowercase();
+        let span = lt..end + 1;
+        if is_closing {
+            if !scan.attributes.is_empty() {
+                self.error(ParseErrorKind::EndTagWithAttributes, span.clone());
+            }
+            if scan.self_closing {
+                self.error(ParseErrorKind::EndTagWithTrailingSolidus, span.clone());
+            }
+            return Markup::Token(RawToken::EndTag { name, span });
+        }
+
+        // Check attributes
+        let mut duplicates = Vec::new();
+        for range in scan.duplicates.iter() {
+            let name = self.html[range.clone()].to_ascii_lowercase();
+            self.error(ParseErrorKind::DuplicateAttribute(name.clone()), range.clone());
+            duplicates.push(name);
+        }
+        for range in scan.invalid.iter() {
+            let name = self.html[range.clone()].to_string();
+            self.error(ParseErrorKind::InvalidAttributeName(name), range.clone());
+        }
+        self.check_references(name_end..scan.attr_end, true);
+
+        Markup::Token(RawToken::StartTag {
+            name,
+            attributes: scan.attributes,
+            duplicates,
+            attr_span: name_end..scan.attr_end,
+            self_closing: scan.self_closing,
+            span,
+        })
+    }
+}
+
+/// Attributes read by the attribute states
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttrScan {
+    pub attributes: Vec<Attribute>,
+    pub duplicates: Vec<Range<usize>>,
+    pub invalid: Vec<Range<usize>>,
+    pub attr_end: usize,
+    pub end: Option<usize>,
+    pub self_closing: bool,
+}
+
+/// Run the attribute states over html from start, until the end of the tag or end of input
+pub fn scan_attributes(html: &str, start: usize) -> AttrScan {
+    let bytes = html.as_bytes();
+    let mut scan = AttrScan::default();
+    let mut state = State::BeforeAttributeName;
+    let mut index = start;
+    let mut attr_name = start..start;
+    let mut attr_value = start..start;
+
+    while index < bytes.len() {
+        let c = bytes[index];
+        match state {
+            State::BeforeAttributeName => match c {
+                b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' => {}
+                b'/' => {
+                    scan.attr_end = index;
+                    state = State::SelfClosingStartTag;
+                }
+                b'>' => {
+                    scan.attr_end = index;
+                    break;
+                }
+                _ => {
+                    attr_name = index..index + 1;
+                    attr_value = index..index;
+                    state = State::AttributeName;
+                }
+            },
+            State::AttributeName => match c {
+                b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' => {
+                    state = State::AfterAttributeName;
+                }
+                b'/' | b'>' => {
+                    scan.add(html, &attr_name, &attr_value, QuoteStyle::Bare);
+                    scan.attr_end = index;
+                    if c == b'>' {
+                        break;
+                    }
+                    state = State::SelfClosingStartTag;
+                }
+                b'=' => {
+                    state = State::BeforeAttributeValue;
+                }
+                _ => {
+                    attr_name.end = index + 1;
+                }
+            },
+            State::AfterAttributeName => match c {
+                b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' => {}
+                b'=' => {
+                    state = State::BeforeAttributeValue;
+                }
+                b'/' | b'>' => {
+                    scan.add(html, &attr_name, &attr_value, QuoteStyle::Bare);
+                    scan.attr_end = index;
+                    if c == b'>' {
+                        break;
+                    }
+                    state = State::SelfClosingStartTag;
+                }
+                _ => {
+                    scan.add(html, &attr_name, &attr_value, QuoteStyle::Bare);
+                    attr_name = index..index + 1;
+                    attr_value = index..index;
+                    state = State::AttributeName;
+                }
+            },
+            State::BeforeAttributeValue => match c {
+                b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' => {}
+                b'"' => {
+                    attr_value = index + 1..index + 1;
+                    state = State::AttributeValueDoubleQuoted;
+                }
+                b'\'' => {
+                    attr_value = index + 1..index + 1;
+                    state = State::AttributeValueSingleQuoted;
+                }
+                b'>' => {
+                    scan.add(html, &attr_name, &attr_value, QuoteStyle::Unquoted);
+                    scan.attr_end = index;
+                    break;
+                }
+                _ => {
+                    attr_value = index..index + 1;
+                    state = State::AttributeValueUnquoted;
+                }
+            },
+            State::AttributeValueDoubleQuoted | State::AttributeValueSingleQuoted => {
+                let (quote, quote_style) = if state == State::AttributeValueDoubleQuoted {
+                    (b'"', QuoteStyle::Double)
+                } else {
+                    (b'\'', QuoteStyle::Single)
+                };
+                if c == quote {
+                    scan.add(html, &attr_name, &attr_value, quote_style);
+                    state = State::AfterAttributeValueQuoted;
+                } else {
+                    attr_value.end = index + 1;
+                }
+            }
+            State::AttributeValueUnquoted => match c {
+                b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' => {
+                    scan.add(html, &attr_name, &attr_value, QuoteStyle::Unquoted);
+                    state = State::BeforeAttributeName;
+                }
+                b'>' => {
+                    scan.add(html, &attr_name, &attr_value, QuoteStyle::Unquoted);
+                    scan.attr_end = index;
+                    break;
+                }
+                _ => {
+                    attr_value.end = index + 1;
+                }
+            },
+            State::AfterAttributeValueQuoted => match c {
+                b'\t' | b'\n' | b'\x0c' | b'\r' | b' ' => {
+                    state = State::BeforeAttributeName;
+                }
+                b'/' => {
+                    scan.attr_end = index;
+                    state = State::SelfClosingStartTag;
+                }
+                b'>' => {
+                    scan.attr_end = index;
+                    break;
+                }
+                _ => {
+                    state = State::BeforeAttributeName;
+                    continue;
+                }
+            },
+            State::SelfClosingStartTag => match c {
+                b'>' => {
+                    scan.self_closing = true;
+                    break;
+                }
+                _ => {
+                    state = State::BeforeAttributeName;
+                    continue;
+                }
+            },
+        }
+        index += 1;
+    }
+
+    // End of input, finish any attribute in progress
+    if index >= bytes.len() {
+        let quote = match state {
+            State::AttributeName | State::AfterAttributeName => Some(QuoteStyle::Bare),
+            State::BeforeAttributeValue | State::AttributeValueUnquoted => Some(QuoteStyle::Unquoted),
+            State::AttributeValueDoubleQuoted => Some(QuoteStyle::Double),
+            State::AttributeValueSingleQuoted => Some(QuoteStyle::Single),
+            _ => None,
+        };
+        if let Some(quote) = quote {
+            scan.add(html, &attr_name, &attr_value, quote);
+        }
+        if state != State::SelfClosingStartTag {
+            scan.attr_end = bytes.len();
+        }
+        return scan;
+    }
+
+    scan.end = Some(index);
+    scan
+}
+
+impl AttrScan {
+    /// Add attribute, first occurrence of a duplicate name wins
+    fn add(&mut self, html: &str, name: &Range<usize>, value: &Range<usize>, quote: QuoteStyle) {
+        let key = &html[name.clone()];
+
+        // Names with quotes, '<' or a leading '=' are parse errors, but kept as written
+        if key.contains(['"', '\'', '<']) || key.starts_with('=') {
+            self.invalid.push(name.clone());
+        }
+        if self.attributes.iter().any(|attr| attr.is_named(key)) {
+            self.duplicates.push(name.clone());
+            return;
+        }
+
+        self.attributes.push(Attribute::with_quote(key, &html[value.clone()], quote));
+    }
+}
+
+/// Check if character ends a tag name
+fn is_tag_name_end(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0c' | '\r' | ' ' | '/' | '>')
+}
+
+impl Iterator for Tokenizer<'_> {
+    type Item = RawToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(token) = self.pending.pop_front() {
+            return Some(token);
+        } else if self.pos >= self.html.len() {
+            return None;
+        }
+
+        // Text within raw text elements may contain anything, all other text is checked for character references
+        let is_raw = self.end_tag.as_deref().is_some_and(is_raw_text);
+        let token = if self.end_tag.is_some() { self.raw_text() } else { self.data() };
+        if let Some(RawToken::Text { span }) = &token {
+            if !is_raw {
+                self.check_references(span.clone(), false);
+            }
+        }
+        token
+    }
+}
+
+/// Get end byte offset of a token
+fn token_end(token: &RawToken) -> usize {
+    match token {
+        RawToken::Text { span }
+        | RawToken::StartTag { span, .. }
+        | RawToken::EndTag { span, .. }
+        | RawToken::Comment { span }
+        | RawToken::Doctype { span }
+        | RawToken::CData { span }
+        | RawToken::ProcessingInstruction { span } => span.end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scan_attributes, RawToken, Tokenizer};
+    use crate::{parse_attr, parse_attributes, parse_html, parse_html_with_diagnostics, ParseErrorKind, QuoteStyle};
+
+    /// Get tokens as their kind and source, along with the kinds of errors
+    fn tokenize(html: &str) -> (Vec<(&'static str, &str)>, Vec<ParseErrorKind>) {
+        let mut tokenizer = Tokenizer::new(html);
+        let tokens = tokenizer
+            .by_ref()
+            .map(|token| match token {
+                RawToken::Text { span } => ("text", &html[span]),
+                RawToken::StartTag { span, .. } => ("start", &html[span]),
+                RawToken::EndTag { span, .. } => ("end", &html[span]),
+                RawToken::Comment { span } => ("comment", &html[span]),
+                RawToken::Doctype { span } => ("doctype", &html[span]),
+                RawToken::CData { span } => ("cdata", &html[span]),
+                RawToken::ProcessingInstruction { span } => ("pi", &html[span]),
+            })
+            .collect();
+        let errors = tokenizer.take_errors().iter().map(|error| error.kind()).collect();
+        (tokens, errors)
+    }
+
+    #[test]
+    fn tags_text_and_comments() {
+        let (tokens, errors) = tokenize("<P class=\"a>b\">one<!-- x -- y --></p >two");
+        assert_eq!(
+            tokens,
+            [
+                ("start", "<P class=\"a>b\">"),
+                ("text", "one"),
+                ("comment", "<!-- x -- y -->"),
+                ("end", "</p >"),
+                ("text", "two")
+            ]
+        );
+        assert!(errors.is_empty());
+
+        let mut tokenizer = Tokenizer::new("<DIV id=x />");
+        match tokenizer.next() {
+            Some(RawToken::StartTag { name, self_closing, attr_span, .. }) => {
+                assert_eq!(name, "div");
+                assert!(self_closing);
+                assert_eq!(attr_span, 4..10);
+            }
+            token => panic!("unexpected token {:?}", token),
+        }
+    }
+
+    #[test]
+    fn less_than_without_markup_is_text() {
+        let (tokens, errors) = tokenize("a < b <3 <");
+        assert_eq!(tokens, [("text", "a < b <3 <")]);
+        assert_eq!(
+            errors,
+            [
+                ParseErrorKind::InvalidFirstCharacterOfTagName,
+                ParseErrorKind::InvalidFirstCharacterOfTagName,
+                ParseErrorKind::EofInTag
+            ]
+        );
+
+        let (tokens, errors) = tokenize("a</>b</ x>c");
+        assert_eq!(tokens, [("text", "a</>b"), ("comment", "</ x>"), ("text", "c")]);
+        assert_eq!(errors, [ParseErrorKind::MissingEndTagName, ParseErrorKind::InvalidFirstCharacterOfTagName]);
+    }
+
+    #[test]
+    fn eof_inside_tag_is_text() {
+        let (tokens, errors) = tokenize("text<div class=\"a");
+        assert_eq!(tokens, [("text", "text<div class=\"a")]);
+        assert_eq!(errors, [ParseErrorKind::EofInTag]);
+
+        let (tokens, errors) = tokenize("<p>one</p");
+        assert_eq!(tokens, [("start", "<p>"), ("text", "one</p")]);
+        assert_eq!(errors, [ParseErrorKind::EofInTag]);
+    }
+
+    #[test]
+    fn eof_inside_comment_runs_to_end() {
+        let (tokens, errors) = tokenize("a<!-- open <p>b");
+        assert_eq!(tokens, [("text", "a"), ("comment", "<!-- open <p>b")]);
+        assert_eq!(errors, [ParseErrorKind::EofInComment]);
+
+        let (tokens, errors) = tokenize("<!--><!---><!-- a --!><!x>");
+        assert_eq!(tokens, [("comment", "<!-->"), ("comment", "<!--->"), ("comment", "<!-- a --!>"), ("comment", "<!x>")]);
+        assert_eq!(
+            errors,
+            [
+                ParseErrorKind::AbruptClosingOfEmptyComment,
+                ParseErrorKind::AbruptClosingOfEmptyComment,
+                ParseErrorKind::IncorrectlyClosedComment,
+                ParseErrorKind::IncorrectlyOpenedComment
+            ]
+        );
+    }
+
+    #[test]
+    fn end_tag_with_attributes_or_solidus() {
+        let (tokens, errors) = tokenize("<b>x</b class=y></b/>");
+        assert_eq!(tokens, [("start", "<b>"), ("text", "x"), ("end", "</b class=y>"), ("end", "</b/>")]);
+        assert_eq!(errors, [ParseErrorKind::EndTagWithAttributes, ParseErrorKind::EndTagWithTrailingSolidus]);
+    }
+
+    #[test]
+    fn raw_text_runs_to_matching_end_tag() {
+        let (tokens, errors) = tokenize("<script>if (a < b && c) { x = '</p>'; }</scripty></SCRIPT >after");
+        assert_eq!(
+            tokens,
+            [
+                ("start", "<script>"),
+                ("text", "if (a < b && c) { x = '</p>'; }</scripty>"),
+                ("end", "</SCRIPT >"),
+                ("text", "after")
+            ]
+        );
+        assert!(errors.is_empty());
+
+        // Trailing solidus is only known to be ignored once the tree builder opens the element
+        let html = "<style></style><script/><b>x</b></script>";
+        let mut tokenizer = Tokenizer::new(html);
+        assert!(matches!(tokenizer.nth(2), Some(RawToken::StartTag { self_closing: true, .. })));
+        tokenizer.open_element("script");
+        assert_eq!(tokenizer.next(), Some(RawToken::Text { span: 24..32 }));
+
+        let stack = parse_html(html);
+        assert_eq!(stack.query().tag("script").to_vec()[0].contents(), "<b>x</b>");
+        assert!(stack.query().tag("b").to_vec().is_empty());
+        assert_eq!(stack.render(), html);
+
+        let stack = parse_html("<svg><script/><b>x</b></svg>");
+        assert_eq!(stack.query().tag("b").to_vec().len(), 1);
+    }
+
+    #[test]
+    fn raw_text_without_end_tag_runs_to_eof() {
+        let (tokens, errors) = tokenize("<style>a { } <!-- <b>");
+        assert_eq!(tokens, [("start", "<style>"), ("text", "a { } <!-- <b>")]);
+        assert!(errors.is_empty());
+
+        let (tokens, _) = tokenize("<title>one</title");
+        assert_eq!(tokens, [("start", "<title>"), ("text", "one</title")]);
+    }
+
+    #[test]
+    fn rcdata_checks_character_references() {
+        let (tokens, errors) = tokenize("<textarea><b>&bogus;</b></textarea><script>&bogus;</script>");
+        assert_eq!(tokens[1], ("text", "<b>&bogus;</b>"));
+        assert_eq!(tokens[4], ("text", "&bogus;"));
+        assert_eq!(errors, [ParseErrorKind::InvalidCharacterReference("&bogus;".to_string())]);
+
+        let (stack, _) = parse_html_with_diagnostics("<title>a &amp; <b>b</b></title><p>c</p>");
+        let title = stack.query().tag("title").to_vec()[0];
+        assert_eq!(title.contents(), "a & <b>b</b>");
+        assert!(stack.query().tag("b").to_vec().is_empty());
+        assert_eq!(stack.query().tag("p").to_vec().len(), 1);
+    }
+
+    /// Get attributes as name, raw value and quote style
+    fn attributes(attr_string: &str) -> Vec<(String, String, QuoteStyle)> {
+        let (attributes, _) = parse_attributes(attr_string);
+        attributes.iter().map(|attr| (attr.name(), attr.raw_value(), attr.quote())).collect()
+    }
+
+    #[test]
+    fn attribute_quoting_styles() {
+        assert_eq!(
+            attributes(" a=\"1 2\" b='3 \"4\"' c=5/6 d = \"7\"e=x"),
+            [
+                ("a".to_string(), "1 2".to_string(), QuoteStyle::Double),
+                ("b".to_string(), "3 \"4\"".to_string(), QuoteStyle::Single),
+                ("c".to_string(), "5/6".to_string(), QuoteStyle::Unquoted),
+                ("d".to_string(), "7".to_string(), QuoteStyle::Double),
+                ("e".to_string(), "x".to_string(), QuoteStyle::Unquoted),
+            ]
+        );
+        assert_eq!(attributes("a= >"), [("a".to_string(), String::new(), QuoteStyle::Unquoted)]);
+    }
+
+    #[test]
+    fn boolean_attributes() {
+        assert_eq!(
+            attributes("disabled checked=\"\" hidden /"),
+            [
+                ("disabled".to_string(), String::new(), QuoteStyle::Bare),
+                ("checked".to_string(), String::new(), QuoteStyle::Double),
+                ("hidden".to_string(), String::new(), QuoteStyle::Bare),
+            ]
+        );
+
+        let scan = scan_attributes("<input disabled/>", 6);
+        assert!(scan.self_closing);
+        assert_eq!(scan.end, Some(16));
+        assert_eq!(scan.attributes[0].name(), "disabled");
+    }
+
+    #[test]
+    fn duplicate_attributes_keep_first() {
+        let (stack, errors) = parse_html_with_diagnostics("<p id=a class=x ID=b id='c'>");
+        let p = stack.query().tag("p").to_vec()[0];
+        assert_eq!(p.attr("id"), Some("a".to_string()));
+        assert_eq!(p.attributes().len(), 2);
+        assert_eq!(p.duplicate_attrs(), ["id", "id"]);
+        assert_eq!(
+            errors.iter().map(|error| (error.kind(), error.span().start)).collect::<Vec<_>>(),
+            [
+                (ParseErrorKind::DuplicateAttribute("id".to_string()), 16),
+                (ParseErrorKind::DuplicateAttribute("id".to_string()), 21)
+            ]
+        );
+        assert_eq!(stack.render(), "<p id=a class=x ID=b id='c'>");
+    }
+
+    #[test]
+    fn framework_attribute_names() {
+        let (stack, errors) = parse_html_with_diagnostics("<button @click=\"go()\" :class='{a: b}' v-on:submit.prevent x-data></button>");
+        let button = stack.query().tag("button").to_vec()[0];
+        assert_eq!(button.attr("@click"), Some("go()".to_string()));
+        assert_eq!(button.attr(":class"), Some("{a: b}".to_string()));
+        assert!(button.has_attr("v-on:submit.prevent"));
+        assert_eq!(button.attr("x-data"), Some(String::new()));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn unterminated_attributes_end_at_eof() {
+        let scan = scan_attributes("<a href='x", 2);
+        assert_eq!(scan.end, None);
+        assert_eq!(scan.attributes[0].raw_value(), "x");
+        assert_eq!(scan.attributes[0].quote(), QuoteStyle::Single);
+    }
+
+    #[test]
+    fn invalid_attribute_names_are_kept_and_reported() {
+        let (stack, errors) = parse_html_with_diagnostics("<div a\"b=1 c<d x='y'z=2>");
+        let div = stack.query().tag("div").to_vec()[0];
+        let names: Vec<String> = div.attributes().iter().map(|attr| attr.name()).collect();
+        assert_eq!(names, ["a\"b", "c<d", "x", "z"]);
+        assert_eq!(div.attr("c<d"), Some(String::new()));
+        assert_eq!(div.attr_extra(), "");
+
+        let kinds: Vec<ParseErrorKind> = errors.iter().map(|error| error.kind()).collect();
+        assert!(kinds.contains(&ParseErrorKind::InvalidAttributeName("a\"b".to_string())));
+        assert!(kinds.contains(&ParseErrorKind::InvalidAttributeName("c<d".to_string())));
+        assert_eq!(stack.render(), "<div a\"b=1 c<d x='y'z=2>");
+    }
+
+    #[test]
+    fn parse_attr_keeps_map_signature() {
+        let (attr, extra) = parse_attr("id=main class=\"a &amp; b\" disabled");
+        assert_eq!(attr.get("class").map(|r| r.as_str()), Some("a & b"));
+        assert_eq!(attr.get("disabled").map(|r| r.as_str()), Some(""));
+        assert_eq!(extra, "");
+
+        let (attributes, duplicates) = parse_attributes("a=1 A=2 b");
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(duplicates, ["a"]);
+    }
+}
@@ -0,0 +1,1437 @@
+use crate::elements::{is_raw_text, is_rcdata};
+use crate::error::XPathError;
+use crate::stack::Stack;
+use crate::token::{NodeKind, Token};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Parsed XPath 1.0 expression, eg. `//div[@class='card']/ul/li[position() > 1]/a/@href`
+#[derive(Debug, Clone, PartialEq)]
+pub struct XPath {
+    expr: Expr,
+}
+
+/// Result of evaluating an XPath expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum XPathValue {
+    /// Ids of tokens in document order, for use with `Stack::get()`, `get_mut()` and the navigation and mutation methods
+    Nodes(Vec<usize>),
+    /// String values of a node-set which includes attributes or the text of raw text elements, in document order
+    Values(Vec<String>),
+    String(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+    Union(Box<Expr>, Box<Expr>),
+    Path(PathStart, Vec<Step>),
+    Filter(Box<Expr>, Vec<Expr>),
+    Literal(String),
+    Number(f64),
+    Function(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+/// Where a location path starts from
+#[derive(Debug, Clone, PartialEq)]
+enum PathStart {
+    Root,
+    Context,
+    Filter(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    axis: Axis,
+    test: NodeTest,
+    predicates: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Axis {
+    Child,
+    Descendant,
+    DescendantOrSelf,
+    Parent,
+    Ancestor,
+    AncestorOrSelf,
+    FollowingSibling,
+    PrecedingSibling,
+    Following,
+    Preceding,
+    Attribute,
+    Itself,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NodeTest {
+    Any,
+    Name(String),
+    Node,
+    Text,
+    Comment,
+    ProcessingInstruction(Option<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Last,
+    Position,
+    Count,
+    LocalName,
+    Name,
+    String,
+    Concat,
+    StartsWith,
+    Contains,
+    SubstringBefore,
+    SubstringAfter,
+    Substring,
+    StringLength,
+    NormalizeSpace,
+    Translate,
+    Boolean,
+    Not,
+    True,
+    False,
+    Number,
+    Sum,
+    Floor,
+    Ceiling,
+    Round,
+}
+
+impl Function {
+    /// Get function by name, along with its minimum and maximum number of arguments
+    fn lookup(name: &str) -> Option<(Self, usize, usize)> {
+        let res = match name {
+            "last" => (Self::Last, 0, 0),
+            "position" => (Self::Position, 0, 0),
+            "count" => (Self::Count, 1, 1),
+            "local-name" => (Self::LocalName, 0, 1),
+            "name" => (Self::Name, 0, 1),
+            "string" => (Self::String, 0, 1),
+            "concat" => (Self::Concat, 2, usize::MAX),
+            "starts-with" => (Self::StartsWith, 2, 2),
+            "contains" => (Self::Contains, 2, 2),
+            "substring-before" => (Self::SubstringBefore, 2, 2),
+            "substring-after" => (Self::SubstringAfter, 2, 2),
+            "substring" => (Self::Substring, 2, 3),
+            "string-length" => (Self::StringLength, 0, 1),
+            "normalize-space" => (Self::NormalizeSpace, 0, 1),
+            "translate" => (Self::Translate, 3, 3),
+            "boolean" => (Self::Boolean, 1, 1),
+            "not" => (Self::Not, 1, 1),
+            "true" => (Self::True, 0, 0),
+            "false" => (Self::False, 0, 0),
+            "number" => (Self::Number, 0, 1),
+            "sum" => (Self::Sum, 1, 1),
+            "floor" => (Self::Floor, 1, 1),
+            "ceiling" => (Self::Ceiling, 1, 1),
+            "round" => (Self::Round, 1, 1),
+            _ => return None,
+        };
+        Some(res)
+    }
+}
+
+impl XPath {
+    /// Parse XPath expression once, so it can be evaluated against any number of stacks
+    pub fn parse(expression: &str) -> Result<Self, XPathError> {
+        let tokens = lex(expression).map_err(|pos| XPathError::new(expression, &pos))?;
+        let mut parser = Parser { tokens, index: 0, end: expression.len() };
+        let expr = parser.expr().map_err(|pos| XPathError::new(expression, &pos))?;
+        if parser.index < parser.tokens.len() {
+            return Err(XPathError::new(expression, &parser.tokens[parser.index].1));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Evaluate expression against the whole document
+    pub fn evaluate(&self, stack: &Stack) -> XPathValue {
+        self.evaluate_at(stack, Node::Root)
+    }
+
+    /// Evaluate expression with token as the context node, eg. for relative paths such as `a/@href`
+    pub fn evaluate_from(&self, stack: &Stack, token_id: &usize) -> XPathValue {
+        if !stack.tokens.contains_key(token_id) {
+            return XPathValue::Nodes(Vec::new());
+        }
+        self.evaluate_at(stack, Node::Token(*token_id))
+    }
+
+    /// Evaluate expression at context node
+    fn evaluate_at(&self, stack: &Stack, node: Node) -> XPathValue {
+        let doc = Document::new(stack);
+        let context = Context { node, position: 1, size: 1 };
+        match doc.eval(&self.expr, &context) {
+            Value::Nodes(nodes) => {
+                if nodes.iter().all(|node| matches!(node, Node::Token(_))) {
+                    let token_ids = nodes
+                        .iter()
+                        .filter_map(|node| match node {
+                            Node::Token(id) => Some(*id),
+                            _ => None,
+                        })
+                        .collect();
+                    XPathValue::Nodes(token_ids)
+                } else {
+                    XPathValue::Values(nodes.iter().map(|node| doc.string_value(node)).collect())
+                }
+            }
+            Value::String(r) => XPathValue::String(r),
+            Value::Number(r) => XPathValue::Number(r),
+            Value::Boolean(r) => XPathValue::Boolean(r),
+        }
+    }
+}
+
+impl FromStr for XPath {
+    type Err = XPathError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        Self::parse(expression)
+    }
+}
+
+/// Node within the XPath data model.  Raw text and RCDATA elements (eg. script, title) keep their
+/// text on the element itself, so it is exposed as a body text node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Node {
+    Root,
+    Token(usize),
+    Attr(usize, usize),
+    Body(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Nodes(Vec<Node>),
+    String(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+/// Context of evaluation, the context node and its position within the current node-set
+struct Context {
+    node: Node,
+    position: usize,
+    size: usize,
+}
+
+/// Stack being evaluated, along with the document order of its tokens
+struct Document<'a> {
+    stack: &'a Stack,
+    order: HashMap<usize, usize>,
+    all: Vec<Node>,
+}
+
+impl<'a> Document<'a> {
+    /// Index stack in document order
+    fn new(stack: &'a Stack) -> Self {
+        let mut doc = Self { stack, order: HashMap::new(), all: vec![Node::Root] };
+        let mut pending = doc.children(&Node::Root);
+        pending.reverse();
+        while let Some(node) = pending.pop() {
+            if let Node::Token(id) = node {
+                doc.order.insert(id, doc.order.len() + 1);
+            }
+            doc.all.push(node);
+            let mut children = doc.children(&node);
+            children.reverse();
+            pending.extend(children);
+        }
+        doc
+    }
+
+    /// Get key to sort nodes in document order
+    fn order_key(&self, node: &Node) -> (usize, usize, usize) {
+        match node {
+            Node::Root => (0, 0, 0),
+            Node::Token(id) => (self.order.get(id).copied().unwrap_or(usize::MAX), 0, 0),
+            Node::Attr(id, index) => (self.order.get(id).copied().unwrap_or(usize::MAX), 1, *index),
+            Node::Body(id) => (self.order.get(id).copied().unwrap_or(usize::MAX), 2, 0),
+        }
+    }
+
+    /// Sort nodes in document order and remove duplicates
+    fn sort(&self, nodes: &mut Vec<Node>) {
+        nodes.sort_by_key(|node| self.order_key(node));
+        nodes.dedup();
+    }
+
+    /// Get token of node
+    fn token(&self, node: &Node) -> Option<&Token> {
+        match node {
+            Node::Token(id) => self.stack.tokens.get(id),
+            _ => None,
+        }
+    }
+
+    /// Get children of node
+    fn children(&self, node: &Node) -> Vec<Node> {
+        match node {
+            Node::Root => self.stack.child_ids(&0).iter().copied().map(Node::Token).collect(),
+            Node::Token(id) => {
+                let token = self.stack.tokens.get(id).unwrap();
+                if token.kind() != NodeKind::Element {
+                    return Vec::new();
+                } else if (is_raw_text(&token.tag()) || is_rcdata(&token.tag())) && !token.raw_contents().is_empty() {
+                    return vec![Node::Body(*id)];
+                }
+                self.stack.child_ids(id).iter().copied().map(Node::Token).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Get parent of node
+    fn parent(&self, node: &Node) -> Option<Node> {
+        match node {
+            Node::Root => None,
+            Node::Token(id) => match self.stack.tokens.get(id)?.parent_id() {
+                0 => Some(Node::Root),
+                parent_id => Some(Node::Token(parent_id)),
+            },
+            Node::Attr(id, _) | Node::Body(id) => Some(Node::Token(*id)),
+        }
+    }
+
+    /// Get all descendants of node in document order
+    fn descendants(&self, node: &Node) -> Vec<Node> {
+        let mut res = Vec::new();
+        for child in self.children(node) {
+            res.push(child);
+            res.extend(self.descendants(&child));
+        }
+        res
+    }
+
+    /// Get ancestors of node, nearest first
+    fn ancestors(&self, node: &Node) -> Vec<Node> {
+        let mut res = Vec::new();
+        let mut current = self.parent(node);
+        while let Some(parent) = current {
+            res.push(parent);
+            current = self.parent(&parent);
+        }
+        res
+    }
+
+    /// Get siblings of node, before and after it
+    fn siblings(&self, node: &Node) -> (Vec<Node>, Vec<Node>) {
+        if !matches!(node, Node::Token(_)) {
+            return (Vec::new(), Vec::new());
+        }
+        let siblings = match self.parent(node) {
+            Some(parent) => self.children(&parent),
+            None => return (Vec::new(), Vec::new()),
+        };
+        let pos = siblings.iter().position(|sibling| sibling == node).unwrap();
+        (siblings[..pos].to_vec(), siblings[pos + 1..].to_vec())
+    }
+
+    /// Get nodes along axis, in document order
+    fn axis(&self, node: &Node, axis: Axis) -> Vec<Node> {
+        match axis {
+            Axis::Child => self.children(node),
+            Axis::Descendant => self.descendants(node),
+            Axis::DescendantOrSelf => {
+                let mut res = vec![*node];
+                res.extend(self.descendants(node));
+                res
+            }
+            Axis::Parent => self.parent(node).into_iter().collect(),
+            Axis::Ancestor => {
+                let mut res = self.ancestors(node);
+                res.reverse();
+                res
+            }
+            Axis::AncestorOrSelf => {
+                let mut res = self.ancestors(node);
+                res.reverse();
+                res.push(*node);
+                res
+            }
+            Axis::FollowingSibling => self.siblings(node).1,
+            Axis::PrecedingSibling => self.siblings(node).0,
+            Axis::Following => {
+                let key = self.order_key(node);
+                let descendants = self.descendants(node);
+                self.all
+                    .iter()
+                    .filter(|other| self.order_key(other) > key && !descendants.contains(other))
+                    .copied()
+                    .collect()
+            }
+            Axis::Preceding => {
+                let key = self.order_key(node);
+                let ancestors = self.ancestors(node);
+                self.all
+                    .iter()
+                    .filter(|other| self.order_key(other) < key && !ancestors.contains(other))
+                    .copied()
+                    .collect()
+            }
+            Axis::Attribute => match self.token(node) {
+                Some(token) if token.kind() == NodeKind::Element => {
+                    (0..token.attributes().len()).map(|index| Node::Attr(token.id(), index)).collect()
+                }
+                _ => Vec::new(),
+            },
+            Axis::Itself => vec![*node],
+        }
+    }
+
+    /// Check if node passes node test, for the principal node type of axis
+    fn test(&self, node: &Node, test: &NodeTest, axis: Axis) -> bool {
+        let kind = match node {
+            Node::Root => None,
+            Node::Token(id) => Some(self.stack.tokens.get(id).unwrap().kind()),
+            Node::Attr(..) => None,
+            Node::Body(_) => Some(NodeKind::Text),
+        };
+
+        match test {
+            NodeTest::Node => true,
+            NodeTest::Text => matches!(kind, Some(NodeKind::Text) | Some(NodeKind::CData)),
+            NodeTest::Comment => kind == Some(NodeKind::Comment),
+            NodeTest::ProcessingInstruction(target) => {
+                kind == Some(NodeKind::ProcessingInstruction)
+                    && target.as_ref().is_none_or(|target| self.name(node) == *target)
+            }
+            NodeTest::Any if axis == Axis::Attribute => matches!(node, Node::Attr(..)),
+            NodeTest::Any => kind == Some(NodeKind::Element),
+            NodeTest::Name(name) if axis == Axis::Attribute => {
+                matches!(node, Node::Attr(..)) && self.name(node).eq_ignore_ascii_case(name)
+            }
+            NodeTest::Name(name) => kind == Some(NodeKind::Element) && self.name(node).eq_ignore_ascii_case(name),
+        }
+    }
+
+    /// Get name of node, blank if it has none
+    fn name(&self, node: &Node) -> String {
+        match node {
+            Node::Token(id) => {
+                let token = self.stack.tokens.get(id).unwrap();
+                match token.kind() {
+                    NodeKind::Element => token.tag(),
+                    NodeKind::ProcessingInstruction => {
+                        let body = token.raw_contents();
+                        let body = body.get(2..).unwrap_or("");
+                        body.split(|c: char| c.is_ascii_whitespace() || c == '?' || c == '>')
+                            .next()
+                            .unwrap_or("")
+                            .to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            Node::Attr(id, index) => {
+                self.stack.tokens.get(id).unwrap().attributes()[*index].name().to_ascii_lowercase()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Get string value of node
+    fn string_value(&self, node: &Node) -> String {
+        match node {
+            Node::Root => self.stack.child_ids(&0).iter().map(|id| self.string_value(&Node::Token(*id))).collect(),
+            Node::Token(id) => {
+                let token = self.stack.tokens.get(id).unwrap();
+                match token.kind() {
+                    NodeKind::Element | NodeKind::Text => self.stack.get_text(id),
+                    NodeKind::Comment => token.comment_text().unwrap_or_default(),
+                    NodeKind::CData => token.cdata_text().unwrap_or_default(),
+                    _ => String::new(),
+                }
+            }
+            Node::Attr(id, index) => self.stack.tokens.get(id).unwrap().attributes()[*index].value(),
+            Node::Body(id) => self.stack.tokens.get(id).unwrap().contents(),
+        }
+    }
+
+    /// Evaluate expression
+    fn eval(&self, expr: &Expr, context: &Context) -> Value {
+        match expr {
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                Value::Boolean(self.boolean(&self.eval(left, context)) || self.boolean(&self.eval(right, context)))
+            }
+            Expr::Binary(BinaryOp::And, left, right) => {
+                Value::Boolean(self.boolean(&self.eval(left, context)) && self.boolean(&self.eval(right, context)))
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left, context);
+                let right = self.eval(right, context);
+                match op {
+                    BinaryOp::Add => Value::Number(self.number(&left) + self.number(&right)),
+                    BinaryOp::Sub => Value::Number(self.number(&left) - self.number(&right)),
+                    BinaryOp::Mul => Value::Number(self.number(&left) * self.number(&right)),
+                    BinaryOp::Div => Value::Number(self.number(&left) / self.number(&right)),
+                    BinaryOp::Mod => Value::Number(self.number(&left) % self.number(&right)),
+                    _ => Value::Boolean(self.compare(*op, &left, &right)),
+                }
+            }
+            Expr::Negate(inner) => Value::Number(-self.number(&self.eval(inner, context))),
+            Expr::Union(left, right) => {
+                let mut nodes = self.nodes(self.eval(left, context));
+                nodes.extend(self.nodes(self.eval(right, context)));
+                self.sort(&mut nodes);
+                Value::Nodes(nodes)
+            }
+            Expr::Path(start, steps) => {
+                let mut nodes = match start {
+                    PathStart::Root => vec![Node::Root],
+                    PathStart::Context => vec![context.node],
+                    PathStart::Filter(filter) => self.nodes(self.eval(filter, context)),
+                };
+                for step in steps.iter() {
+                    nodes = self.step(&nodes, step);
+                }
+                Value::Nodes(nodes)
+            }
+            Expr::Filter(inner, predicates) => {
+                let value = self.eval(inner, context);
+                if predicates.is_empty() {
+                    return value;
+                }
+                let mut nodes = self.nodes(value);
+                for predicate in predicates.iter() {
+                    nodes = self.filter(&nodes, predicate);
+                }
+                Value::Nodes(nodes)
+            }
+            Expr::Literal(r) => Value::String(r.clone()),
+            Expr::Number(r) => Value::Number(*r),
+            Expr::Function(function, args) => self.call(*function, args, context),
+        }
+    }
+
+    /// Apply location step to each node, returns node-set in document order
+    fn step(&self, nodes: &[Node], step: &Step) -> Vec<Node> {
+        let is_reverse =
+            matches!(step.axis, Axis::Ancestor | Axis::AncestorOrSelf | Axis::Preceding | Axis::PrecedingSibling);
+
+        let mut res = Vec::new();
+        for node in nodes.iter() {
+            let mut selected: Vec<Node> = self
+                .axis(node, step.axis)
+                .into_iter()
+                .filter(|other| self.test(other, &step.test, step.axis))
+                .collect();
+
+            // Predicates use proximity positions, which run backwards on reverse axes
+            if is_reverse {
+                selected.reverse();
+            }
+            for predicate in step.predicates.iter() {
+                selected = self.filter(&selected, predicate);
+            }
+            res.extend(selected);
+        }
+
+        self.sort(&mut res);
+        res
+    }
+
+    /// Filter node-set by predicate, a number predicate matches the node at that position
+    fn filter(&self, nodes: &[Node], predicate: &Expr) -> Vec<Node> {
+        let size = nodes.len();
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(index, node)| {
+                let context = Context { node: **node, position: index + 1, size };
+                match self.eval(predicate, &context) {
+                    Value::Number(r) => r == (index + 1) as f64,
+                    value => self.boolean(&value),
+                }
+            })
+            .map(|(_, node)| *node)
+            .collect()
+    }
+
+    /// Call function
+    fn call(&self, function: Function, args: &[Expr], context: &Context) -> Value {
+        let arg = |index: usize| self.eval(&args[index], context);
+        let string_arg = |index: usize| match args.get(index) {
+            Some(expr) => self.string(&self.eval(expr, context)),
+            None => self.string_value(&context.node),
+        };
+        let node_arg = |index: usize| match args.get(index) {
+            Some(expr) => self.nodes(self.eval(expr, context)).first().copied(),
+            None => Some(context.node),
+        };
+
+        match function {
+            Function::Last => Value::Number(context.size as f64),
+            Function::Position => Value::Number(context.position as f64),
+            Function::Count => Value::Number(self.nodes(arg(0)).len() as f64),
+            Function::LocalName | Function::Name => {
+                Value::String(node_arg(0).map(|node| self.name(&node)).unwrap_or_default())
+            }
+            Function::String => Value::String(string_arg(0)),
+            Function::Concat => Value::String((0..args.len()).map(string_arg).collect()),
+            Function::StartsWith => Value::Boolean(string_arg(0).starts_with(&string_arg(1))),
+            Function::Contains => Value::Boolean(string_arg(0).contains(&string_arg(1))),
+            Function::SubstringBefore => {
+                let (text, search) = (string_arg(0), string_arg(1));
+                Value::String(text.find(&search).map(|r| text[..r].to_string()).unwrap_or_default())
+            }
+            Function::SubstringAfter => {
+                let (text, search) = (string_arg(0), string_arg(1));
+                Value::String(text.find(&search).map(|r| text[r + search.len()..].to_string()).unwrap_or_default())
+            }
+            Function::Substring => {
+                // Positions are 1-based and rounded, characters at positions >= start and < start + length are kept
+                let text = string_arg(0);
+                let start = round(self.number(&arg(1)));
+                let end = match args.len() {
+                    3 => start + round(self.number(&arg(2))),
+                    _ => f64::INFINITY,
+                };
+                let res = text
+                    .chars()
+                    .enumerate()
+                    .filter(|(index, _)| {
+                        let pos = (*index + 1) as f64;
+                        pos >= start && pos < end
+                    })
+                    .map(|(_, c)| c)
+                    .collect();
+                Value::String(res)
+            }
+            Function::StringLength => Value::Number(string_arg(0).chars().count() as f64),
+            Function::NormalizeSpace => {
+                Value::String(string_arg(0).split_ascii_whitespace().collect::<Vec<&str>>().join(" "))
+            }
+            Function::Translate => {
+                let (text, from, to) = (string_arg(0), string_arg(1), string_arg(2));
+                let from: Vec<char> = from.chars().collect();
+                let to: Vec<char> = to.chars().collect();
+                let res = text
+                    .chars()
+                    .filter_map(|c| match from.iter().position(|f| *f == c) {
+                        Some(index) => to.get(index).copied(),
+                        None => Some(c),
+                    })
+                    .collect();
+                Value::String(res)
+            }
+            Function::Boolean => Value::Boolean(self.boolean(&arg(0))),
+            Function::Not => Value::Boolean(!self.boolean(&arg(0))),
+            Function::True => Value::Boolean(true),
+            Function::False => Value::Boolean(false),
+            Function::Number => match args.is_empty() {
+                true => Value::Number(to_number(&self.string_value(&context.node))),
+                false => Value::Number(self.number(&arg(0))),
+            },
+            Function::Sum => Value::Number(
+                self.nodes(arg(0)).iter().fold(0.0, |sum, node| sum + to_number(&self.string_value(node))),
+            ),
+            Function::Floor => Value::Number(self.number(&arg(0)).floor()),
+            Function::Ceiling => Value::Number(self.number(&arg(0)).ceil()),
+            Function::Round => Value::Number(round(self.number(&arg(0)))),
+        }
+    }
+
+    /// Compare two values, as per the XPath rules for node-sets
+    fn compare(&self, op: BinaryOp, left: &Value, right: &Value) -> bool {
+        match (left, right) {
+            (Value::Nodes(left), Value::Nodes(right)) => left.iter().any(|l| {
+                let l = Value::String(self.string_value(l));
+                right.iter().any(|r| self.compare(op, &l, &Value::String(self.string_value(r))))
+            }),
+            (Value::Nodes(_), Value::Boolean(_)) => self.compare(op, &Value::Boolean(self.boolean(left)), right),
+            (Value::Boolean(_), Value::Nodes(_)) => self.compare(op, left, &Value::Boolean(self.boolean(right))),
+            (Value::Nodes(nodes), other) => {
+                nodes.iter().any(|node| self.compare(op, &self.node_as(node, other), other))
+            }
+            (other, Value::Nodes(nodes)) => {
+                nodes.iter().any(|node| self.compare(op, other, &self.node_as(node, other)))
+            }
+            _ => match op {
+                BinaryOp::Eq | BinaryOp::Ne => {
+                    let is_equal = match (left, right) {
+                        (Value::Boolean(_), _) | (_, Value::Boolean(_)) => self.boolean(left) == self.boolean(right),
+                        (Value::Number(_), _) | (_, Value::Number(_)) => self.number(left) == self.number(right),
+                        _ => self.string(left) == self.string(right),
+                    };
+                    is_equal == (op == BinaryOp::Eq)
+                }
+                BinaryOp::Lt => self.number(left) < self.number(right),
+                BinaryOp::Le => self.number(left) <= self.number(right),
+                BinaryOp::Gt => self.number(left) > self.number(right),
+                _ => self.number(left) >= self.number(right),
+            },
+        }
+    }
+
+    /// Convert node to the same type as other value, for comparisons
+    fn node_as(&self, node: &Node, other: &Value) -> Value {
+        let value = self.string_value(node);
+        match other {
+            Value::Number(_) => Value::Number(to_number(&value)),
+            _ => Value::String(value),
+        }
+    }
+
+    /// Get value as node-set, empty if it is not one
+    fn nodes(&self, value: Value) -> Vec<Node> {
+        match value {
+            Value::Nodes(nodes) => nodes,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Convert value to string
+    fn string(&self, value: &Value) -> String {
+        match value {
+            Value::Nodes(nodes) => nodes.first().map(|node| self.string_value(node)).unwrap_or_default(),
+            Value::String(r) => r.clone(),
+            Value::Number(r) => number_to_string(*r),
+            Value::Boolean(r) => r.to_string(),
+        }
+    }
+
+    /// Convert value to number
+    fn number(&self, value: &Value) -> f64 {
+        match value {
+            Value::Number(r) => *r,
+            Value::Boolean(r) => {
+                if *r {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            _ => to_number(&self.string(value)),
+        }
+    }
+
+    /// Convert value to boolean
+    fn boolean(&self, value: &Value) -> bool {
+        match value {
+            Value::Nodes(nodes) => !nodes.is_empty(),
+            Value::String(r) => !r.is_empty(),
+            Value::Number(r) => *r != 0.0 && !r.is_nan(),
+            Value::Boolean(r) => *r,
+        }
+    }
+}
+
+/// Convert string to number, NaN if it is not one
+fn to_number(text: &str) -> f64 {
+    let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+    let is_valid = !text.is_empty()
+        && text.strip_prefix('-').unwrap_or(text).chars().all(|c| c.is_ascii_digit() || c == '.')
+        && text.matches('.').count() <= 1
+        && text != "."
+        && text != "-.";
+    match is_valid {
+        true => text.parse::<f64>().unwrap_or(f64::NAN),
+        false => f64::NAN,
+    }
+}
+
+/// Convert number to string, integers are written without a decimal point
+fn number_to_string(number: f64) -> String {
+    if number.is_nan() {
+        "NaN".to_string()
+    } else if number.is_infinite() {
+        if number > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else if number == number.trunc() && number.abs() < 1e15 {
+        format!("{}", number as i64)
+    } else {
+        format!("{}", number)
+    }
+}
+
+/// Round to nearest integer, with halves rounded towards positive infinity
+fn round(number: f64) -> f64 {
+    if number.is_nan() || number.is_infinite() {
+        return number;
+    }
+    (number + 0.5).floor()
+}
+
+/// Lexical token of an XPath expression
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Slash,
+    DoubleSlash,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Dot,
+    DotDot,
+    At,
+    Comma,
+    ColonColon,
+    Pipe,
+    Plus,
+    Minus,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Multiply,
+    Operator(BinaryOp),
+    Literal(String),
+    Number(f64),
+    NameTest(String),
+    NodeType(String),
+    FunctionName(String),
+    AxisName(String),
+}
+
+/// Split expression into tokens with their byte positions, returns position of offending character on error
+fn lex(expression: &str) -> Result<Vec<(Lexeme, usize)>, usize> {
+    let bytes = expression.as_bytes();
+    let mut res: Vec<(Lexeme, usize)> = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        if c.is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        // An operator is expected if the previous token may end an operand
+        let expects_operator = match res.last() {
+            None => false,
+            Some((prev, _)) => !matches!(
+                prev,
+                Lexeme::At
+                    | Lexeme::ColonColon
+                    | Lexeme::LParen
+                    | Lexeme::LBracket
+                    | Lexeme::Comma
+                    | Lexeme::Operator(_)
+                    | Lexeme::Slash
+                    | Lexeme::DoubleSlash
+                    | Lexeme::Pipe
+                    | Lexeme::Plus
+                    | Lexeme::Minus
+                    | Lexeme::Eq
+                    | Lexeme::Ne
+                    | Lexeme::Lt
+                    | Lexeme::Le
+                    | Lexeme::Gt
+                    | Lexeme::Ge
+                    | Lexeme::Multiply
+            ),
+        };
+
+        let start = pos;
+        let next = bytes.get(pos + 1).copied();
+        let lexeme = match c {
+            b'/' if next == Some(b'/') => {
+                pos += 2;
+                Lexeme::DoubleSlash
+            }
+            b'/' => {
+                pos += 1;
+                Lexeme::Slash
+            }
+            b'(' | b')' | b'[' | b']' | b'@' | b',' | b'|' | b'+' | b'-' | b'=' => {
+                pos += 1;
+                match c {
+                    b'(' => Lexeme::LParen,
+                    b')' => Lexeme::RParen,
+                    b'[' => Lexeme::LBracket,
+                    b']' => Lexeme::RBracket,
+                    b'@' => Lexeme::At,
+                    b',' => Lexeme::Comma,
+                    b'|' => Lexeme::Pipe,
+                    b'+' => Lexeme::Plus,
+                    b'-' => Lexeme::Minus,
+                    _ => Lexeme::Eq,
+                }
+            }
+            b'!' if next == Some(b'=') => {
+                pos += 2;
+                Lexeme::Ne
+            }
+            b'<' | b'>' => {
+                let is_equal = next == Some(b'=');
+                pos += if is_equal { 2 } else { 1 };
+                match (c, is_equal) {
+                    (b'<', false) => Lexeme::Lt,
+                    (b'<', true) => Lexeme::Le,
+                    (_, false) => Lexeme::Gt,
+                    _ => Lexeme::Ge,
+                }
+            }
+            b':' if next == Some(b':') => {
+                pos += 2;
+                Lexeme::ColonColon
+            }
+            b'"' | b'\'' => {
+                let end = match expression[pos + 1..].find(c as char) {
+                    Some(r) => pos + 1 + r,
+                    None => return Err(pos),
+                };
+                let literal = expression[pos + 1..end].to_string();
+                pos = end + 1;
+                Lexeme::Literal(literal)
+            }
+            b'.' if next == Some(b'.') => {
+                pos += 2;
+                Lexeme::DotDot
+            }
+            b'.' if !next.is_some_and(|n| n.is_ascii_digit()) => {
+                pos += 1;
+                Lexeme::Dot
+            }
+            b'0'..=b'9' | b'.' => {
+                while pos < bytes.len() && (bytes[pos].is_ascii_digit() || bytes[pos] == b'.') {
+                    pos += 1;
+                }
+                match expression[start..pos].parse::<f64>() {
+                    Ok(r) => Lexeme::Number(r),
+                    Err(_) => return Err(start),
+                }
+            }
+            b'*' if expects_operator => {
+                pos += 1;
+                Lexeme::Multiply
+            }
+            b'*' => {
+                pos += 1;
+                Lexeme::NameTest("*".to_string())
+            }
+            _ if is_name_start(expression[pos..].chars().next().unwrap()) => {
+                pos = name_end(expression, pos);
+
+                // Prefixed name, or prefix with wildcard (eg. 'svg:*')
+                if bytes.get(pos) == Some(&b':') && bytes.get(pos + 1) != Some(&b':') {
+                    if bytes.get(pos + 1) == Some(&b'*') {
+                        pos += 2;
+                    } else if expression[pos + 1..].chars().next().is_some_and(is_name_start) {
+                        pos = name_end(expression, pos + 1);
+                    }
+                }
+                let name = expression[start..pos].to_string();
+
+                // Disambiguate operator names, functions, node types and axes
+                let after = expression[pos..].trim_start();
+                if expects_operator {
+                    match name.as_str() {
+                        "and" => Lexeme::Operator(BinaryOp::And),
+                        "or" => Lexeme::Operator(BinaryOp::Or),
+                        "div" => Lexeme::Operator(BinaryOp::Div),
+                        "mod" => Lexeme::Operator(BinaryOp::Mod),
+                        _ => return Err(start),
+                    }
+                } else if after.starts_with("::") {
+                    Lexeme::AxisName(name)
+                } else if after.starts_with('(') {
+                    match name.as_str() {
+                        "comment" | "text" | "processing-instruction" | "node" => Lexeme::NodeType(name),
+                        _ => Lexeme::FunctionName(name),
+                    }
+                } else {
+                    Lexeme::NameTest(name)
+                }
+            }
+            _ => return Err(pos),
+        };
+        res.push((lexeme, start));
+    }
+
+    Ok(res)
+}
+
+/// Check if character may start a name
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+/// Get end of name starting at position
+fn name_end(expression: &str, pos: usize) -> usize {
+    let len = expression[pos..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' || !c.is_ascii()))
+        .unwrap_or(expression.len() - pos);
+    pos + len
+}
+
+/// Recursive descent parser over lexical tokens, returns byte position of offending token on error
+struct Parser {
+    tokens: Vec<(Lexeme, usize)>,
+    index: usize,
+    end: usize,
+}
+
+impl Parser {
+    /// Expression, starting at the lowest precedence
+    fn expr(&mut self) -> Result<Expr, usize> {
+        self.binary(0)
+    }
+
+    /// Binary operators by precedence level, lowest first
+    fn binary(&mut self, level: usize) -> Result<Expr, usize> {
+        if level == 5 {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = match (level, self.peek()) {
+                (0, Some(Lexeme::Operator(BinaryOp::Or))) => BinaryOp::Or,
+                (1, Some(Lexeme::Operator(BinaryOp::And))) => BinaryOp::And,
+                (2, Some(Lexeme::Eq)) => BinaryOp::Eq,
+                (2, Some(Lexeme::Ne)) => BinaryOp::Ne,
+                (3, Some(Lexeme::Lt)) => BinaryOp::Lt,
+                (3, Some(Lexeme::Le)) => BinaryOp::Le,
+                (3, Some(Lexeme::Gt)) => BinaryOp::Gt,
+                (3, Some(Lexeme::Ge)) => BinaryOp::Ge,
+                (4, Some(Lexeme::Plus)) => BinaryOp::Add,
+                (4, Some(Lexeme::Minus)) => BinaryOp::Sub,
+                _ => break,
+            };
+            self.index += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    /// Unary minus and multiplicative operators
+    fn unary(&mut self) -> Result<Expr, usize> {
+        let mut left = self.negate()?;
+        loop {
+            let op = match self.peek() {
+                Some(Lexeme::Multiply) => BinaryOp::Mul,
+                Some(Lexeme::Operator(BinaryOp::Div)) => BinaryOp::Div,
+                Some(Lexeme::Operator(BinaryOp::Mod)) => BinaryOp::Mod,
+                _ => break,
+            };
+            self.index += 1;
+            let right = self.negate()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    /// Unary minus
+    fn negate(&mut self) -> Result<Expr, usize> {
+        if self.eat(&Lexeme::Minus) {
+            return Ok(Expr::Negate(Box::new(self.negate()?)));
+        }
+        self.union()
+    }
+
+    /// Union of paths
+    fn union(&mut self) -> Result<Expr, usize> {
+        let mut left = self.path()?;
+        while self.eat(&Lexeme::Pipe) {
+            let right = self.path()?;
+            left = Expr::Union(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    /// Location path, or filter expression optionally followed by a relative path
+    fn path(&mut self) -> Result<Expr, usize> {
+        match self.peek() {
+            Some(Lexeme::Slash) => {
+                self.index += 1;
+                let steps = if self.starts_step() { self.relative_path()? } else { Vec::new() };
+                Ok(Expr::Path(PathStart::Root, steps))
+            }
+            Some(Lexeme::DoubleSlash) => {
+                self.index += 1;
+                let mut steps = vec![descendant_or_self()];
+                steps.extend(self.relative_path()?);
+                Ok(Expr::Path(PathStart::Root, steps))
+            }
+            Some(Lexeme::LParen)
+            | Some(Lexeme::Literal(_))
+            | Some(Lexeme::Number(_))
+            | Some(Lexeme::FunctionName(_)) => {
+                let primary = self.primary()?;
+                let mut predicates = Vec::new();
+                while self.peek() == Some(&Lexeme::LBracket) {
+                    predicates.push(self.predicate()?);
+                }
+                let filter = if predicates.is_empty() { primary } else { Expr::Filter(Box::new(primary), predicates) };
+
+                // Relative path after filter expression, eg. `(//ul)[1]/li`
+                let mut steps = Vec::new();
+                match self.peek() {
+                    Some(Lexeme::Slash) => {
+                        self.index += 1;
+                        steps = self.relative_path()?;
+                    }
+                    Some(Lexeme::DoubleSlash) => {
+                        self.index += 1;
+                        steps.push(descendant_or_self());
+                        steps.extend(self.relative_path()?);
+                    }
+                    _ => return Ok(filter),
+                }
+                Ok(Expr::Path(PathStart::Filter(Box::new(filter)), steps))
+            }
+            _ => Ok(Expr::Path(PathStart::Context, self.relative_path()?)),
+        }
+    }
+
+    /// Relative location path, steps separated by '/' or '//'
+    fn relative_path(&mut self) -> Result<Vec<Step>, usize> {
+        let mut steps = vec![self.step()?];
+        loop {
+            match self.peek() {
+                Some(Lexeme::Slash) => {
+                    self.index += 1;
+                }
+                Some(Lexeme::DoubleSlash) => {
+                    self.index += 1;
+                    steps.push(descendant_or_self());
+                }
+                _ => return Ok(steps),
+            }
+            steps.push(self.step()?);
+        }
+    }
+
+    /// Check if next token starts a location step
+    fn starts_step(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Lexeme::Dot)
+                | Some(Lexeme::DotDot)
+                | Some(Lexeme::At)
+                | Some(Lexeme::AxisName(_))
+                | Some(Lexeme::NameTest(_))
+                | Some(Lexeme::NodeType(_))
+        )
+    }
+
+    /// Location step, eg. `child::li[2]`, `@href`, `..`
+    fn step(&mut self) -> Result<Step, usize> {
+        let pos = self.position();
+        if self.eat(&Lexeme::Dot) {
+            return Ok(Step { axis: Axis::Itself, test: NodeTest::Node, predicates: Vec::new() });
+        } else if self.eat(&Lexeme::DotDot) {
+            return Ok(Step { axis: Axis::Parent, test: NodeTest::Node, predicates: Vec::new() });
+        }
+
+        // Get axis
+        let axis = match self.peek().cloned() {
+            Some(Lexeme::At) => {
+                self.index += 1;
+                Axis::Attribute
+            }
+            Some(Lexeme::AxisName(name)) => {
+                let axis = match name.as_str() {
+                    "child" => Axis::Child,
+                    "descendant" => Axis::Descendant,
+                    "descendant-or-self" => Axis::DescendantOrSelf,
+                    "parent" => Axis::Parent,
+                    "ancestor" => Axis::Ancestor,
+                    "ancestor-or-self" => Axis::AncestorOrSelf,
+                    "following-sibling" => Axis::FollowingSibling,
+                    "preceding-sibling" => Axis::PrecedingSibling,
+                    "following" => Axis::Following,
+                    "preceding" => Axis::Preceding,
+                    "attribute" => Axis::Attribute,
+                    "self" => Axis::Itself,
+                    _ => return Err(pos),
+                };
+                self.index += 1;
+                if !self.eat(&Lexeme::ColonColon) {
+                    return Err(self.position());
+                }
+                axis
+            }
+            _ => Axis::Child,
+        };
+
+        // Get node test
+        let pos = self.position();
+        let test = match self.peek().cloned() {
+            Some(Lexeme::NameTest(name)) if name == "*" => NodeTest::Any,
+            Some(Lexeme::NameTest(name)) => NodeTest::Name(name),
+            Some(Lexeme::NodeType(name)) => {
+                self.index += 1;
+                if !self.eat(&Lexeme::LParen) {
+                    return Err(self.position());
+                }
+                let mut target = None;
+                if let Some(Lexeme::Literal(r)) = self.peek().cloned() {
+                    if name != "processing-instruction" {
+                        return Err(self.position());
+                    }
+                    target = Some(r);
+                    self.index += 1;
+                }
+                if self.peek() != Some(&Lexeme::RParen) {
+                    return Err(self.position());
+                }
+                match name.as_str() {
+                    "comment" => NodeTest::Comment,
+                    "text" => NodeTest::Text,
+                    "processing-instruction" => NodeTest::ProcessingInstruction(target),
+                    _ => NodeTest::Node,
+                }
+            }
+            _ => return Err(pos),
+        };
+        self.index += 1;
+
+        // Get predicates
+        let mut predicates = Vec::new();
+        while self.peek() == Some(&Lexeme::LBracket) {
+            predicates.push(self.predicate()?);
+        }
+
+        Ok(Step { axis, test, predicates })
+    }
+
+    /// Predicate, eg. `[2]` or `[@class='active']`
+    fn predicate(&mut self) -> Result<Expr, usize> {
+        self.index += 1;
+        let expr = self.expr()?;
+        if !self.eat(&Lexeme::RBracket) {
+            return Err(self.position());
+        }
+        Ok(expr)
+    }
+
+    /// Primary expression, a parenthesized expression, literal, number or function call
+    fn primary(&mut self) -> Result<Expr, usize> {
+        let pos = self.position();
+        match self.peek().cloned() {
+            Some(Lexeme::LParen) => {
+                self.index += 1;
+                let expr = self.expr()?;
+                if !self.eat(&Lexeme::RParen) {
+                    return Err(self.position());
+                }
+                Ok(expr)
+            }
+            Some(Lexeme::Literal(r)) => {
+                self.index += 1;
+                Ok(Expr::Literal(r))
+            }
+            Some(Lexeme::Number(r)) => {
+                self.index += 1;
+                Ok(Expr::Number(r))
+            }
+            Some(Lexeme::FunctionName(name)) => {
+                let (function, min, max) = Function::lookup(&name).ok_or(pos)?;
+                self.index += 2;
+
+                // Get arguments
+                let mut args = Vec::new();
+                if !self.eat(&Lexeme::RParen) {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.eat(&Lexeme::RParen) {
+                            break;
+                        } else if !self.eat(&Lexeme::Comma) {
+                            return Err(self.position());
+                        }
+                    }
+                }
+                if args.len() < min || args.len() > max {
+                    return Err(pos);
+                }
+                Ok(Expr::Function(function, args))
+            }
+            _ => Err(pos),
+        }
+    }
+
+    /// Consume token if it is next
+    fn eat(&mut self, lexeme: &Lexeme) -> bool {
+        if self.peek() == Some(lexeme) {
+            self.index += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Get next token
+    fn peek(&self) -> Option<&Lexeme> {
+        self.tokens.get(self.index).map(|(lexeme, _)| lexeme)
+    }
+
+    /// Get byte position of next token, or end of expression
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map(|(_, pos)| *pos).unwrap_or(self.end)
+    }
+}
+
+/// Step for the '//' abbreviation
+fn descendant_or_self() -> Step {
+    Step { axis: Axis::DescendantOrSelf, test: NodeTest::Node, predicates: Vec::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_html, Stack, XPath, XPathValue};
+
+    const DOCUMENT: &str = "<html><body><div id=main class=\"card big\"><h1>Title</h1><ul><li>a</li><li class=x>b</li>\
+        <li>c</li></ul><p>one <b>two</b> three</p><!-- note --></div><p id=end lang=en>4</p></body></html>";
+
+    /// Evaluate expression, with nodes given as their tag and text
+    fn eval(stack: &Stack, expression: &str) -> XPathValue {
+        match stack.xpath(expression).unwrap() {
+            XPathValue::Nodes(token_ids) => XPathValue::Values(
+                token_ids
+                    .iter()
+                    .map(|token_id| {
+                        let token = stack.get(token_id).unwrap();
+                        format!("{}:{}", token.tag(), stack.get_text(token_id))
+                    })
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    /// Get values as expected from eval()
+    fn values(values: &[&str]) -> XPathValue {
+        XPathValue::Values(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn axes() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(eval(&stack, "/html/body/p"), values(&["p:4"]));
+        assert_eq!(eval(&stack, "//ul/child::li[1]"), values(&["li:a"]));
+        assert_eq!(eval(&stack, "count(//div/descendant::*)"), XPathValue::Number(7.0));
+        assert_eq!(eval(&stack, "count(//ul/descendant-or-self::*)"), XPathValue::Number(4.0));
+        assert_eq!(eval(&stack, "//b/parent::*"), values(&["p:one two three"]));
+        assert_eq!(eval(&stack, "//b/.."), values(&["p:one two three"]));
+        assert_eq!(eval(&stack, "name(//b/ancestor::*[2])"), XPathValue::String("div".to_string()));
+        assert_eq!(eval(&stack, "count(//b/ancestor-or-self::*)"), XPathValue::Number(5.0));
+        assert_eq!(eval(&stack, "//li[1]/following-sibling::li"), values(&["li:b", "li:c"]));
+        assert_eq!(eval(&stack, "//li[3]/preceding-sibling::li[1]"), values(&["li:b"]));
+        assert_eq!(eval(&stack, "//h1/following::p"), values(&["p:one two three", "p:4"]));
+        assert_eq!(eval(&stack, "//p[@id]/preceding::li"), values(&["li:a", "li:b", "li:c"]));
+        assert_eq!(eval(&stack, "//p/attribute::id"), values(&["end"]));
+        assert_eq!(eval(&stack, "//li/self::li[@class]"), values(&["li:b"]));
+        assert_eq!(eval(&stack, "//li[2]/."), values(&["li:b"]));
+    }
+
+    #[test]
+    fn node_tests() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(eval(&stack, "string(//div/comment())"), XPathValue::String(" note ".to_string()));
+        assert_eq!(eval(&stack, "string(//p/text()[2])"), XPathValue::String(" three".to_string()));
+        assert_eq!(eval(&stack, "count(//ul/node())"), XPathValue::Number(3.0));
+        assert_eq!(eval(&stack, "//div/@*"), values(&["main", "card big"]));
+        assert_eq!(eval(&stack, "count(//processing-instruction())"), XPathValue::Number(0.0));
+    }
+
+    #[test]
+    fn predicates() {
+        let stack = parse_html(DOCUMENT);
+        assert_eq!(eval(&stack, "//li[position() > 1]"), values(&["li:b", "li:c"]));
+        assert_eq!(eval(&stack, "//li[last()]"), values(&["li:c"]));
+        assert_eq!(eval(&stack, "//li[@class='x']"), values(&["li:b"]));
+        assert_eq!(eval(&stack, "//li[. = 'c' or @class]"), values(&["li:b", "li:c"]));
+        assert_eq!(eval(&stack, "//p[b][1]"), values(&["p:one two three"]));
+        assert_eq!(eval(&stack, "(//li)[2]"), values(&["li:b"]));
+        assert_eq!(eval(&stack, "//li[2][@class]"), values(&["li:b"]));
+        assert_eq!(eval(&stack, "//li[not(@class)][2]"), values(&["li:c"]));
+        assert_eq!(eval(&stack, "//h1 | //p[@id]"), values(&["h1:Title", "p:4"]));
+    }
+
+    #[test]
+    fn functions() {
+        let stack = parse_html(DOCUMENT);
+        let string = |value: &str| XPathValue::String(value.to_string());
+        assert_eq!(eval(&stack, "count(//li)"), XPathValue::Number(3.0));
+        assert_eq!(eval(&stack, "concat(name(//h1), '-', //h1)"), string("h1-Title"));
+        assert_eq!(eval(&stack, "local-name(//p[@lang]/@lang)"), string("lang"));
+        assert_eq!(eval(&stack, "starts-with(//h1, 'Ti')"), XPathValue::Boolean(true));
+        assert_eq!(eval(&stack, "//div[contains(@class, 'big')]/h1"), values(&["h1:Title"]));
+        assert_eq!(eval(&stack, "substring-before('a-b', '-')"), string("a"));
+        assert_eq!(eval(&stack, "substring-after('a-b', '-')"), string("b"));
+        assert_eq!(eval(&stack, "substring('12345', 1.5, 2.6)"), string("234"));
+        assert_eq!(eval(&stack, "string-length(//h1)"), XPathValue::Number(5.0));
+        assert_eq!(eval(&stack, "normalize-space('  a   b ')"), string("a b"));
+        assert_eq!(eval(&stack, "translate('bar', 'abc', 'ABC')"), string("BAr"));
+        assert_eq!(eval(&stack, "boolean(//table) or false()"), XPathValue::Boolean(false));
+        assert_eq!(eval(&stack, "not(true()) = false()"), XPathValue::Boolean(true));
+        assert_eq!(eval(&stack, "sum(//p[@id]) + number('2')"), XPathValue::Number(6.0));
+        assert_eq!(eval(&stack, "floor(2.5) + ceiling(2.5) + round(2.5)"), XPathValue::Number(8.0));
+        assert_eq!(eval(&stack, "7 mod 3 * 2 div 4 - -1"), XPathValue::Number(1.5));
+        assert!(matches!(eval(&stack, "number('x')"), XPathValue::Number(n) if n.is_nan()));
+    }
+
+    #[test]
+    fn evaluate_from_context_token() {
+        let stack = parse_html(DOCUMENT);
+        let ul = stack.select("ul").ids()[0];
+        let xpath = XPath::parse("li[2]").unwrap();
+        assert_eq!(xpath.evaluate_from(&stack, &ul), XPathValue::Nodes(vec![stack.select("li.x").ids()[0]]));
+        assert_eq!(xpath.evaluate(&stack), XPathValue::Nodes(Vec::new()));
+    }
+
+    #[test]
+    fn xpath_error_positions() {
+        let cases = [
+            ("", 0),
+            ("//", 2),
+            ("//div[", 6),
+            ("unknown()", 0),
+            ("count()", 0),
+            ("//div/@", 7),
+            ("1 +", 3),
+            ("'abc", 0),
+            ("//div]", 5),
+            ("bogus::div", 0),
+        ];
+        for (expression, position) in cases {
+            let error = XPath::parse(expression).unwrap_err();
+            assert_eq!((error.expression(), error.position()), (expression.to_string(), position));
+        }
+        assert_eq!(XPath::parse("//div]").unwrap_err().to_string(), "invalid XPath '//div]', unexpected ']' at position 5");
+        assert!(parse_html(DOCUMENT).xpath("//div[").is_err());
+    }
+
+    #[test]
+    fn node_results_are_ids_usable_for_mutation() {
+        let mut stack = parse_html("<ul><li>a</li><li>b</li></ul>");
+        let token_ids = match stack.xpath("//li[2]").unwrap() {
+            XPathValue::Nodes(r) => r,
+            other => panic!("expected nodes, got {:?}", other),
+        };
+        assert_eq!(token_ids, stack.select("li:nth-child(2)").ids());
+
+        stack.get_mut(&token_ids[0]).unwrap().set_attr("class", "second");
+        assert_eq!(stack.render(), "<ul><li>a</li><li class=\"second\">b</li></ul>");
+        assert_eq!(stack.prev_sibling(&token_ids[0]).map(|li| stack.get_text(&li.id())), Some("a".to_string()));
+    }
+}
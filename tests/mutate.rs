@@ -0,0 +1,188 @@
+use parsex::{parse_html, NodeKind, Position, Token};
+use std::collections::HashSet;
+
+#[test]
+fn structural_changes() {
+    let html = "<ul class=list>\n  <li>one</li>\n  <li>two</li>\n</ul>";
+    let mut stack = parse_html(html);
+    let ul = stack.query().tag("ul").ids()[0];
+    let items = stack.query().tag("li").ids();
+
+    stack.move_to(&items[1], &ul, &0);
+    stack.remove(&items[0]);
+    let li = stack.append_child(&ul, Token::new(&0, &0, &0, &false, "li", "", "")).unwrap();
+    stack.append_child(&li, Token::node(&0, &0, NodeKind::Text, "three"));
+
+    assert_eq!(stack.render(), "<ul class=list><li>two</li>\n  \n  \n<li>three</li></ul>");
+    assert_eq!(stack.query().tag("li").to_vec().len(), 2);
+}
+
+#[test]
+fn inserted_html() {
+    let html = "<ul>\n  <li>one</li>\n</ul>";
+    let mut stack = parse_html(html);
+    let ul = stack.query().tag("ul").ids()[0];
+
+    let ids = stack.insert_html(&ul, Position::BeforeEnd, "<li class=new>two<li>three &amp; <b>four</b>");
+    assert_eq!(ids.len(), 2);
+    assert_eq!(stack.render(), "<ul>\n  <li>one</li>\n<li class=new>two<li>three &amp; <b>four</b></ul>");
+    assert_eq!(stack.select("ul > li.new + li b").ids().len(), 1);
+}
+
+#[test]
+fn set_contents() {
+    let html = "<div id=main>\n  <p>old <b>text</b></p>\n</div>";
+    let mut stack = parse_html(html);
+    let div = stack.query().id("main").to_vec()[0].clone();
+
+    div.as_mut(&mut stack).set_contents("<p>new</p><p>items</p>");
+    assert_eq!(stack.render(), "<div id=main><p>new</p><p>items</p></div>");
+    assert_eq!(stack.query().tag("b").ids().len(), 0);
+    assert_eq!(stack.query().tag("p").contents("items").ids().len(), 1);
+
+    stack.for_each_match_mut("p", |p| p.set_text("a & b"));
+    assert_eq!(stack.render(), "<div id=main><p>a &amp; b</p><p>a &amp; b</p></div>");
+}
+
+#[test]
+fn wrap_unwrap_clone() {
+    let html = "<div><table><tr><td>1</td></tr></table><p><font>big</font> text</p></div>";
+    let mut stack = parse_html(html);
+
+    let table = stack.query().tag("table").ids()[0];
+    let wrapper = stack.wrap(&table, "<div class=\"table-scroll\">").unwrap();
+    assert_eq!(stack.get(&table).unwrap().parent_id(), wrapper);
+
+    let font = stack.query().tag("font").ids()[0];
+    assert!(stack.unwrap(&font));
+
+    let tr = stack.query().tag("tr").ids()[0];
+    let copy = stack.clone_subtree(&tr).unwrap();
+    assert_ne!(copy, tr);
+    stack.set_text(&stack.query().parent_id(&copy).tag("td").ids()[0], "2");
+
+    assert_eq!(
+        stack.render(),
+        "<div><div class=\"table-scroll\"><table><tr><td>1</td></tr><tr><td>2</td></tr></table></div><p>big text</p></div>"
+    );
+    assert_eq!(stack.select("div.table-scroll > table td").ids().len(), 2);
+}
+
+#[test]
+fn clone_and_graft() {
+    let html = "<body><nav><a href=/>Home</a> <a href=/admin>Admin</a></nav><main><h1>Title</h1></main></body>";
+    let mut stack = parse_html(html);
+    let nav = stack.query().tag("nav").ids()[0];
+    let admin = stack.query().attr("href", "/admin").ids()[0];
+
+    let part = stack.clone_from(&nav, &vec![admin]).unwrap();
+    assert_eq!(part.render(), "<nav><a href=/>Home</a> </nav>");
+    let root = part.query().tag("nav").to_vec()[0];
+    assert_eq!((root.id(), root.parent_id()), (1, 0));
+
+    let main = stack.query().tag("main").ids()[0];
+    let ids = stack.graft(&main, Position::BeforeEnd, &part);
+    assert_eq!(ids.len(), 1);
+    assert_eq!(
+        stack.render(),
+        "<body><nav><a href=/>Home</a> <a href=/admin>Admin</a></nav><main><h1>Title</h1><nav><a href=/>Home</a> </nav></main></body>"
+    );
+    assert_eq!(stack.select("main > nav > a").ids().len(), 1);
+}
+
+#[test]
+fn insert_into_void_or_text_is_empty() {
+    let html = "<p>one<br>two</p>";
+    let mut stack = parse_html(html);
+    let br = stack.query().tag("br").ids()[0];
+    let text = stack.query().kind(NodeKind::Text).ids()[0];
+
+    assert!(stack.insert_html(&br, Position::AfterBegin, "<b>x</b>").is_empty());
+    assert!(stack.insert_html(&text, Position::BeforeEnd, "<b>x</b>").is_empty());
+    assert!(stack.append_child(&br, Token::node(&0, &0, NodeKind::Text, "x")).is_none());
+    assert!(stack.set_inner_html(&br, "<b>x</b>").is_empty());
+    assert!(!stack.move_to(&text, &br, &0));
+    assert_eq!(stack.render(), html);
+}
+
+#[test]
+fn unwrap_root_is_refused() {
+    let html = "<div><p>one</p></div>";
+    let mut stack = parse_html(html);
+
+    assert!(!stack.unwrap(&0));
+    assert!(stack.wrap(&0, "<section>").is_none());
+    assert!(stack.clone_subtree(&0).is_none());
+    assert_eq!(stack.render(), html);
+}
+
+#[test]
+fn move_into_own_descendant_is_refused() {
+    let html = "<div><ul><li><b>one</b></li></ul></div>";
+    let mut stack = parse_html(html);
+    let ul = stack.query().tag("ul").ids()[0];
+    let b = stack.query().tag("b").ids()[0];
+
+    assert!(!stack.move_to(&ul, &ul, &0));
+    assert!(!stack.move_to(&ul, &b, &0));
+    assert!(stack.wrap(&ul, "<ul>").is_some());
+    assert_eq!(stack.query().tag("ul").ids().len(), 2);
+    assert_eq!(stack.select("div > ul > ul > li > b").ids(), vec![b]);
+}
+
+#[test]
+fn operations_on_detached_ids() {
+    let html = "<div><p>one</p><p>two</p></div>";
+    let mut stack = parse_html(html);
+    let div = stack.query().tag("div").ids()[0];
+    let p = stack.query().tag("p").ids()[0];
+
+    assert!(stack.detach(&p));
+    assert!(!stack.detach(&p));
+    assert_eq!(stack.render(), "<div><p>two</p></div>");
+    assert!(stack.insert_html(&p, Position::BeforeBegin, "<hr>").is_empty());
+    assert!(stack.insert_before(&p, Token::node(&0, &0, NodeKind::Text, "x")).is_none());
+    assert!(stack.wrap(&p, "<section>").is_none());
+    assert!(!stack.unwrap(&p));
+    assert!(stack.clone_subtree(&p).is_none());
+    assert_eq!(stack.render(), "<div><p>two</p></div>");
+
+    // Still movable back into the tree
+    assert!(stack.move_to(&p, &div, &1));
+    assert_eq!(stack.render(), "<div><p>two</p><p>one</p></div>");
+
+    // Ids of removed tokens are refused everywhere
+    stack.remove(&p);
+    assert!(stack.remove(&p).is_none());
+    assert!(!stack.move_to(&p, &div, &0));
+    assert!(stack.insert_html(&p, Position::AfterEnd, "<hr>").is_empty());
+    assert!(stack.set_inner_html(&p, "x").is_empty());
+    assert_eq!(stack.render(), "<div><p>two</p></div>");
+}
+
+#[test]
+fn graft_with_colliding_ids() {
+    let html = "<div id=a><p>one</p></div>";
+    let mut stack = parse_html(html);
+    let other = parse_html("<div id=b><p>two</p></div>");
+    let div = stack.query().id("a").ids()[0];
+    assert_eq!(other.query().id("b").ids(), vec![div]);
+
+    let ids = stack.graft(&div, Position::AfterEnd, &other);
+    assert_eq!(ids.len(), 1);
+    assert_ne!(ids[0], div);
+    assert_eq!(stack.render(), "<div id=a><p>one</p></div><div id=b><p>two</p></div>");
+
+    // Copies are independent of the originals with the same ids
+    let p = stack.select("#b > p").ids()[0];
+    stack.set_text(&p, "three");
+    assert_eq!(stack.render(), "<div id=a><p>one</p></div><div id=b><p>three</p></div>");
+    assert_eq!(other.render(), "<div id=b><p>two</p></div>");
+
+    // Grafting a copy of itself
+    let copy = stack.clone();
+    assert_eq!(stack.graft(&0, Position::BeforeEnd, &copy).len(), 2);
+    assert_eq!(stack.query().tag("p").ids().len(), 4);
+    let ids = stack.query().ids();
+    assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+}
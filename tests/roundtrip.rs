@@ -0,0 +1,69 @@
+use parsex::parse_html;
+use proptest::prelude::*;
+use std::fs;
+
+/// Fragments used to generate random documents, including malformed markup
+const FRAGMENTS: [&str; 40] = [
+    "<div>", "</div>", "<p>", "</p>", "<b>", "</b>", "<i>", "</i>", "<a href=\"/x\">", "</a>",
+    "<ul>", "<li>", "</li>", "</ul>", "<table>", "<tr>", "<td>", "</td>", "</table>", "<br>",
+    "<br/>", "<img src=x.png alt='a > b'>", "<input disabled>", "<!-- comment -->", "<!--",
+    "-->", "<script>", "</script>", "<style>", "<textarea>", "</textarea>", "<title>",
+    "&amp;", "&lt;", " text ", "\n", "a < b", "<span class=\"c\" id=s>", "</span>", "</>",
+];
+
+/// Check that rendering an untouched document returns the exact input
+fn assert_roundtrip(html: &str) {
+    let stack = parse_html(html);
+    assert_eq!(stack.render(), html);
+}
+
+#[test]
+fn roundtrip_corpus() {
+    let mut paths: Vec<_> = fs::read_dir("tests/corpus").unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let html = fs::read_to_string(&path).unwrap();
+        assert_roundtrip(&html);
+    }
+}
+
+#[test]
+fn roundtrip_modified_token_only() {
+    let html = "<div  class='a'>\n<P ID=x>one<p>two</div><br>";
+    let mut stack = parse_html(html);
+    let id = stack.query().tag("p").to_vec()[0].id();
+    stack.get_mut(&id).unwrap().set_attr("class", "b");
+
+    assert_eq!(stack.render(), "<div  class='a'>\n<p ID=x class=\"b\">one</p><p>two</div><br>");
+}
+
+#[test]
+fn roundtrip_placeholder_like_raw_text() {
+    let html = "<p>a</p><script>var s = \"<parsex1>\";</script><textarea></parsex1></textarea>";
+    let stack = parse_html(html);
+    assert_eq!(stack.render(), html);
+    assert_eq!(stack.query().tag("textarea").to_vec()[0].contents(), "</parsex1>");
+}
+
+proptest! {
+    #[test]
+    fn roundtrip_fragments(parts in prop::collection::vec(prop::sample::select(&FRAGMENTS[..]), 0..60)) {
+        let html = parts.concat();
+        let stack = parse_html(&html);
+        prop_assert_eq!(stack.render(), html);
+    }
+
+    #[test]
+    fn roundtrip_markup_characters(html in "[<>/!?=\"' a-z&;-]{0,80}") {
+        let stack = parse_html(&html);
+        prop_assert_eq!(stack.render(), html);
+    }
+
+    #[test]
+    fn roundtrip_any_string(html in ".{0,200}") {
+        let stack = parse_html(&html);
+        prop_assert_eq!(stack.render(), html);
+    }
+}